@@ -0,0 +1,611 @@
+use crate::token::*;
+
+#[cfg(test)]
+mod tests;
+
+/// Where the engine keeps the top-level tokens while it runs a program.
+pub trait TokenStore<'a, T> {
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Calls `f` with up to `len` of the tokens from `start` on, in order.
+    fn with_window<R>(
+        &mut self,
+        start: usize,
+        len: usize,
+        f: impl FnOnce(&[&Token<'a, T>]) -> R,
+    ) -> R;
+
+    /// Calls `f` with all of the tokens, in order.
+    fn with_all<R>(&mut self, f: impl FnOnce(&[&Token<'a, T>]) -> R) -> R {
+        let len = self.len();
+        self.with_window(0, len, f)
+    }
+
+    /// Replaces the `len` tokens at `start` with what `f` makes of them,
+    /// returning how many tokens it made.
+    fn replace(
+        &mut self,
+        start: usize,
+        len: usize,
+        f: impl FnOnce(Vec<Token<'a, T>>) -> Vec<Token<'a, T>>,
+    ) -> usize;
+}
+
+impl<'a, T> TokenStore<'a, T> for Vec<Token<'a, T>> {
+    fn len(&self) -> usize {
+        Vec::len(self)
+    }
+
+    fn with_window<R>(
+        &mut self,
+        start: usize,
+        len: usize,
+        f: impl FnOnce(&[&Token<'a, T>]) -> R,
+    ) -> R {
+        let start = start.min(Vec::len(self));
+        let end = start.saturating_add(len).min(Vec::len(self));
+        f(&self[start..end].iter().collect::<Vec<_>>())
+    }
+
+    fn replace(
+        &mut self,
+        start: usize,
+        len: usize,
+        f: impl FnOnce(Vec<Token<'a, T>>) -> Vec<Token<'a, T>>,
+    ) -> usize {
+        let new_tox = f(self.drain(start..start + len).collect());
+        let new_len = new_tox.len();
+        self.splice(start..start, new_tox);
+        new_len
+    }
+}
+
+/// A node of an `Arena`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NodeId(u32);
+
+/// A token without its root, which all of an arena's tokens share.
+#[derive(Debug)]
+struct Parts<'a, T> {
+    t_type: TokenType<'a, T>,
+    data: T,
+    value: Option<Value>,
+}
+
+#[derive(Debug)]
+struct Node<'a, T> {
+    /// `None` once the node has been removed, until it's reused.
+    parts: Option<Parts<'a, T>>,
+    prev: Option<NodeId>,
+    next: Option<NodeId>,
+}
+
+/// Top-level tokens kept in a linked list, so a rewrite relinks the nodes
+/// around it instead of shifting every token after it, as a `Vec` does. The
+/// nodes of removed tokens are reused for the tokens that replace them.
+///
+/// The nodes keep the parts of each token apart from its root, which is kept
+/// once for the whole arena, so all of the tokens have to be from the same
+/// text. Rules are given ordinary `Token`s to match on, which are moved out
+/// of the nodes they look at and back again afterwards.
+#[derive(Debug)]
+pub struct Arena<'a, T> {
+    /// The text the tokens are from.
+    root: &'a str,
+    nodes: Vec<Node<'a, T>>,
+    free: Vec<NodeId>,
+    first: Option<NodeId>,
+    last: Option<NodeId>,
+    len: usize,
+    /// The last position that was looked up and the node there, since
+    /// rewrites tend to happen near each other.
+    cursor: Option<(usize, NodeId)>,
+    /// How many links `seek` has followed, to check that it stays local.
+    hops: usize,
+}
+
+impl<'a, T> Arena<'a, T> {
+    pub fn new(tokens: Vec<Token<'a, T>>) -> Arena<'a, T> {
+        let mut arena = Arena {
+            root: tokens.first().map_or("", |t| t.root),
+            nodes: Vec::with_capacity(tokens.len()),
+            free: vec![],
+            first: None,
+            last: None,
+            len: 0,
+            cursor: None,
+            hops: 0,
+        };
+        arena.link(None, tokens);
+        arena
+    }
+
+    pub fn first(&self) -> Option<NodeId> {
+        self.first
+    }
+
+    pub fn next(&self, id: NodeId) -> Option<NodeId> {
+        self.node(id).next
+    }
+
+    pub fn prev(&self, id: NodeId) -> Option<NodeId> {
+        self.node(id).prev
+    }
+
+    /// The data of the token at `id`, if it's still in the arena.
+    pub fn data(&self, id: NodeId) -> Option<&T> {
+        Some(&self.parts(id)?.data)
+    }
+
+    /// The content of the token at `id`, as `Token::content` gives it, if
+    /// it's still in the arena.
+    pub fn content(&self, id: NodeId) -> Option<&str> {
+        let range = match &self.parts(id)?.t_type {
+            TokenType::Leaf(range) => range.clone(),
+            TokenType::Virtual(_, text) => return Some(text.as_deref().unwrap_or("")),
+            TokenType::Branch(children) => children
+                .iter()
+                .map(Token::content_range)
+                .reduce(|a, b| a.start.min(b.start)..a.end.max(b.end))?,
+        };
+        Some(&self.root[range])
+    }
+
+    /// The ids of the tokens, in order.
+    pub fn ids(&self) -> impl Iterator<Item = NodeId> + '_ {
+        std::iter::successors(self.first, |&id| self.next(id))
+    }
+
+    /// How many links `seek` has followed so far.
+    pub fn hops(&self) -> usize {
+        self.hops
+    }
+
+    /// The id of the token at `index`.
+    pub fn seek(&mut self, index: usize) -> Option<NodeId> {
+        if index >= self.len {
+            return None;
+        }
+        // Start from whichever of the ends and the cursor is closest.
+        let mut from = [(0, self.first), (self.len - 1, self.last)]
+            .into_iter()
+            .chain(self.cursor.map(|(at, id)| (at, Some(id))))
+            .min_by_key(|(at, _)| at.abs_diff(index))
+            .and_then(|(at, id)| Some((at, id?)))?;
+        self.hops += from.0.abs_diff(index);
+        while from.0 < index {
+            from = (from.0 + 1, self.next(from.1)?);
+        }
+        while from.0 > index {
+            from = (from.0 - 1, self.prev(from.1)?);
+        }
+        self.cursor = Some(from);
+        Some(from.1)
+    }
+
+    pub fn into_tokens(mut self) -> Vec<Token<'a, T>> {
+        let ids = self.ids().collect::<Vec<_>>();
+        ids.into_iter().map(|id| self.take(id)).collect()
+    }
+
+    fn parts(&self, id: NodeId) -> Option<&Parts<'a, T>> {
+        self.nodes.get(id.0 as usize)?.parts.as_ref()
+    }
+
+    /// Moves the token at `id` out of its node, which `put` moves it back to.
+    fn take(&mut self, id: NodeId) -> Token<'a, T> {
+        let parts = self.node_mut(id).parts.take().unwrap();
+        Token {
+            root: self.root,
+            t_type: parts.t_type,
+            data: parts.data,
+            value: parts.value,
+        }
+    }
+
+    fn put(&mut self, id: NodeId, token: Token<'a, T>) {
+        if self.root.is_empty() {
+            self.root = token.root;
+        }
+        debug_assert!(
+            token.root.is_empty() || std::ptr::eq(token.root, self.root),
+            "an arena's tokens should all be from the same text"
+        );
+        self.node_mut(id).parts = Some(Parts {
+            t_type: token.t_type,
+            data: token.data,
+            value: token.value,
+        });
+    }
+
+    fn node(&self, id: NodeId) -> &Node<'a, T> {
+        &self.nodes[id.0 as usize]
+    }
+
+    fn node_mut(&mut self, id: NodeId) -> &mut Node<'a, T> {
+        &mut self.nodes[id.0 as usize]
+    }
+
+    /// Links `tokens` in after `after`, or at the start.
+    fn link(&mut self, after: Option<NodeId>, tokens: Vec<Token<'a, T>>) {
+        let mut prev = after;
+        let next = match after {
+            Some(after) => self.next(after),
+            None => self.first,
+        };
+        for token in tokens {
+            let node = Node {
+                parts: None,
+                prev,
+                next,
+            };
+            let id = match self.free.pop() {
+                Some(id) => {
+                    *self.node_mut(id) = node;
+                    id
+                }
+                None => {
+                    self.nodes.push(node);
+                    NodeId((self.nodes.len() - 1) as u32)
+                }
+            };
+            self.put(id, token);
+            match prev {
+                Some(prev) => self.node_mut(prev).next = Some(id),
+                None => self.first = Some(id),
+            }
+            prev = Some(id);
+            self.len += 1;
+        }
+        match next {
+            Some(next) => self.node_mut(next).prev = prev,
+            None => self.last = prev,
+        }
+    }
+
+    /// Unlinks `len` tokens from `first` on, returning them.
+    fn unlink(&mut self, first: Option<NodeId>, len: usize) -> Vec<Token<'a, T>> {
+        if len == 0 {
+            return vec![];
+        }
+        let prev = first.and_then(|id| self.prev(id));
+        let mut at = first;
+        let mut tokens = Vec::with_capacity(len);
+        for _ in 0..len {
+            let id = at.expect("there should be enough tokens to unlink");
+            tokens.push(self.take(id));
+            at = self.next(id);
+            self.free.push(id);
+        }
+        match prev {
+            Some(prev) => self.node_mut(prev).next = at,
+            None => self.first = at,
+        }
+        match at {
+            Some(next) => self.node_mut(next).prev = prev,
+            None => self.last = prev,
+        }
+        self.len -= len;
+        tokens
+    }
+}
+
+impl<'a, T> From<Vec<Token<'a, T>>> for Arena<'a, T> {
+    fn from(tokens: Vec<Token<'a, T>>) -> Arena<'a, T> {
+        Arena::new(tokens)
+    }
+}
+
+impl<'a, T> TokenStore<'a, T> for Arena<'a, T> {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn with_window<R>(
+        &mut self,
+        start: usize,
+        len: usize,
+        f: impl FnOnce(&[&Token<'a, T>]) -> R,
+    ) -> R {
+        let first = self.seek(start);
+        let ids = std::iter::successors(first, |&id| self.next(id))
+            .take(len)
+            .collect::<Vec<_>>();
+        let tokens = ids.iter().map(|&id| self.take(id)).collect::<Vec<_>>();
+        let result = f(&tokens.iter().collect::<Vec<_>>());
+        for (id, token) in ids.into_iter().zip(tokens) {
+            self.put(id, token);
+        }
+        result
+    }
+
+    fn replace(
+        &mut self,
+        start: usize,
+        len: usize,
+        f: impl FnOnce(Vec<Token<'a, T>>) -> Vec<Token<'a, T>>,
+    ) -> usize {
+        let first = self.seek(start);
+        let after = match start {
+            0 => None,
+            _ => match first {
+                Some(first) => self.prev(first),
+                None => self.last,
+            },
+        };
+        let new_tox = f(self.unlink(first, len));
+        let new_len = new_tox.len();
+        self.link(after, new_tox);
+        self.cursor = after.map(|id| (start - 1, id));
+        new_len
+    }
+}
+
+/// A node of a `PositionIndex`.
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    signature: u64,
+    /// The signatures of the whole subtree, or'd together.
+    bits: u64,
+    size: u32,
+    priority: u32,
+    left: u32,
+    right: u32,
+}
+
+const NIL: u32 = u32::MAX;
+
+/// The signature of every top-level token, by position, kept in a balanced
+/// tree where each subtree knows which bits its tokens have between them.
+/// That finds the next token that has some bits, however far away it is, by
+/// walking down the tree, and a rewrite only touches the entries it replaces.
+#[derive(Debug, Clone)]
+pub struct PositionIndex {
+    entries: Vec<Entry>,
+    free: Vec<u32>,
+    root: u32,
+    seed: u64,
+}
+
+impl Default for PositionIndex {
+    fn default() -> PositionIndex {
+        PositionIndex::new([])
+    }
+}
+
+impl PositionIndex {
+    pub fn new(signatures: impl IntoIterator<Item = u64>) -> PositionIndex {
+        let mut index = PositionIndex {
+            entries: vec![],
+            free: vec![],
+            root: NIL,
+            seed: 0x9e37_79b9_7f4a_7c15,
+        };
+        index.root = index.build(signatures);
+        index
+    }
+
+    pub fn len(&self) -> usize {
+        self.size(self.root) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.root == NIL
+    }
+
+    /// The signature of the token at `position`.
+    pub fn get(&self, position: usize) -> Option<u64> {
+        let mut at = self.root;
+        let mut position = position as u32;
+        while at != NIL {
+            let entry = self.entries[at as usize];
+            let here = self.size(entry.left);
+            if position == here {
+                return Some(entry.signature);
+            }
+            (at, position) = match position < here {
+                true => (entry.left, position),
+                false => (entry.right, position - here - 1),
+            };
+        }
+        None
+    }
+
+    /// Replaces the `len` entries at `start` with `signatures`.
+    pub fn replace(&mut self, start: usize, len: usize, signatures: impl IntoIterator<Item = u64>) {
+        let (before, rest) = self.split(self.root, start as u32);
+        let (old, after) = self.split(rest, len as u32);
+        self.release(old);
+        let new = self.build(signatures);
+        let joined = self.merge(before, new);
+        self.root = self.merge(joined, after);
+    }
+
+    /// The first position from `from` on whose signature shares a bit with
+    /// `bits`.
+    pub fn next(&self, from: usize, bits: u64) -> Option<usize> {
+        if bits == u64::MAX {
+            // Every signature has a bit set.
+            return (from < self.len()).then_some(from);
+        }
+        self.next_in(self.root, 0, from, bits)
+    }
+
+    /// The last position before `before` whose signature shares a bit with
+    /// `bits`.
+    pub fn prev(&self, before: usize, bits: u64) -> Option<usize> {
+        if bits == u64::MAX {
+            return before.min(self.len()).checked_sub(1);
+        }
+        self.prev_in(self.root, 0, before, bits)
+    }
+
+    fn next_in(&self, at: u32, offset: usize, from: usize, bits: u64) -> Option<usize> {
+        if at == NIL || self.bits(at) & bits == 0 || offset + self.size(at) as usize <= from {
+            return None;
+        }
+        let entry = self.entries[at as usize];
+        let here = offset + self.size(entry.left) as usize;
+        if from < here {
+            if let Some(found) = self.next_in(entry.left, offset, from, bits) {
+                return Some(found);
+            }
+        }
+        if here >= from && entry.signature & bits != 0 {
+            return Some(here);
+        }
+        self.next_in(entry.right, here + 1, from, bits)
+    }
+
+    fn prev_in(&self, at: u32, offset: usize, before: usize, bits: u64) -> Option<usize> {
+        if at == NIL || self.bits(at) & bits == 0 || offset >= before {
+            return None;
+        }
+        let entry = self.entries[at as usize];
+        let here = offset + self.size(entry.left) as usize;
+        if here + 1 < before {
+            if let Some(found) = self.prev_in(entry.right, here + 1, before, bits) {
+                return Some(found);
+            }
+        }
+        if here < before && entry.signature & bits != 0 {
+            return Some(here);
+        }
+        self.prev_in(entry.left, offset, before, bits)
+    }
+
+    fn size(&self, at: u32) -> u32 {
+        match at {
+            NIL => 0,
+            _ => self.entries[at as usize].size,
+        }
+    }
+
+    fn bits(&self, at: u32) -> u64 {
+        match at {
+            NIL => 0,
+            _ => self.entries[at as usize].bits,
+        }
+    }
+
+    fn update(&mut self, at: u32) {
+        let Entry { left, right, .. } = self.entries[at as usize];
+        let (size, bits) = (
+            1 + self.size(left) + self.size(right),
+            self.bits(left) | self.bits(right),
+        );
+        let entry = &mut self.entries[at as usize];
+        entry.size = size;
+        entry.bits = entry.signature | bits;
+    }
+
+    fn alloc(&mut self, signature: u64) -> u32 {
+        // xorshift, so the tree stays balanced whatever order rewrites
+        // happen in.
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 7;
+        self.seed ^= self.seed << 17;
+        let entry = Entry {
+            signature,
+            bits: signature,
+            size: 1,
+            priority: self.seed as u32,
+            left: NIL,
+            right: NIL,
+        };
+        match self.free.pop() {
+            Some(at) => {
+                self.entries[at as usize] = entry;
+                at
+            }
+            None => {
+                self.entries.push(entry);
+                (self.entries.len() - 1) as u32
+            }
+        }
+    }
+
+    /// Builds a subtree of `signatures` in order, in linear time.
+    fn build(&mut self, signatures: impl IntoIterator<Item = u64>) -> u32 {
+        // The right spine of the tree so far, from the root down.
+        let mut spine: Vec<u32> = vec![];
+        for signature in signatures {
+            let at = self.alloc(signature);
+            let mut last = NIL;
+            while let Some(&top) = spine.last() {
+                if self.entries[top as usize].priority >= self.entries[at as usize].priority {
+                    break;
+                }
+                spine.pop();
+                self.update(top);
+                last = top;
+            }
+            self.entries[at as usize].left = last;
+            if let Some(&top) = spine.last() {
+                self.entries[top as usize].right = at;
+            }
+            spine.push(at);
+        }
+        let mut root = NIL;
+        while let Some(top) = spine.pop() {
+            self.update(top);
+            root = top;
+        }
+        root
+    }
+
+    /// Splits a subtree into its first `count` entries and the rest.
+    fn split(&mut self, at: u32, count: u32) -> (u32, u32) {
+        if at == NIL {
+            return (NIL, NIL);
+        }
+        let Entry { left, right, .. } = self.entries[at as usize];
+        if self.size(left) >= count {
+            let (first, rest) = self.split(left, count);
+            self.entries[at as usize].left = rest;
+            self.update(at);
+            (first, at)
+        } else {
+            let (first, rest) = self.split(right, count - self.size(left) - 1);
+            self.entries[at as usize].right = first;
+            self.update(at);
+            (at, rest)
+        }
+    }
+
+    fn merge(&mut self, first: u32, second: u32) -> u32 {
+        if first == NIL {
+            return second;
+        }
+        if second == NIL {
+            return first;
+        }
+        if self.entries[first as usize].priority > self.entries[second as usize].priority {
+            let right = self.entries[first as usize].right;
+            self.entries[first as usize].right = self.merge(right, second);
+            self.update(first);
+            first
+        } else {
+            let left = self.entries[second as usize].left;
+            self.entries[second as usize].left = self.merge(first, left);
+            self.update(second);
+            second
+        }
+    }
+
+    /// Frees the entries of a subtree for reuse.
+    fn release(&mut self, at: u32) {
+        let mut pending = vec![at];
+        while let Some(at) = pending.pop() {
+            if at != NIL {
+                let Entry { left, right, .. } = self.entries[at as usize];
+                pending.extend([left, right]);
+                self.free.push(at);
+            }
+        }
+    }
+}
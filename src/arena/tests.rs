@@ -0,0 +1,95 @@
+use super::*;
+use test_case::test_case;
+
+/// Replaces each `(start, len, with)` in turn, where `with` is the text of
+/// the new tokens. They're virtual, since an arena's tokens all have to be
+/// from the same text.
+fn replace_all<'a>(store: &mut impl TokenStore<'a, ()>, edits: &[(usize, usize, &'a str)]) {
+    for &(start, len, with) in edits {
+        store.replace(start, len, |_| {
+            with.chars()
+                .map(|c| Token {
+                    root: "",
+                    t_type: TokenType::Virtual(0, Some(c.to_string())),
+                    data: (),
+                    value: None,
+                })
+                .collect()
+        });
+    }
+}
+
+#[test_case("abcd", &[(1, 2, "xy")]; "replacing the middle")]
+#[test_case("abcd", &[(0, 1, ""), (0, 1, "")]; "removing from the start")]
+#[test_case("abcd", &[(4, 0, "xy"), (5, 1, "")]; "adding to the end")]
+#[test_case("abcd", &[(0, 4, "")]; "removing everything")]
+#[test_case("", &[(0, 0, "xyz"), (1, 1, "ab"), (3, 1, "")]; "starting out empty")]
+#[test_case("abcdefgh", &[(6, 1, "x"), (1, 1, "y"), (5, 2, ""), (2, 0, "z")]; "jumping around")]
+pub fn replace_test(text: &str, edits: &[(usize, usize, &str)]) {
+    let mut vec = Token::token_vec_from_str(text, |_, _| ());
+    let mut arena = Arena::new(Token::token_vec_from_str(text, |_, _| ()));
+    replace_all(&mut vec, edits);
+    replace_all(&mut arena, edits);
+    assert_eq!(arena.len, vec.len());
+    assert_eq!(
+        Token::vec_content(&arena.into_tokens()),
+        Token::vec_content(&vec)
+    );
+}
+
+#[test]
+pub fn reuse_test() {
+    let mut arena = Arena::new(Token::token_vec_from_str("abcd", |_, _| ()));
+    replace_all(&mut arena, &[(1, 2, "x"), (1, 1, "yz")]);
+    assert_eq!(arena.nodes.len(), 4);
+    let content = arena
+        .ids()
+        .map(|id| arena.content(id).unwrap())
+        .collect::<String>();
+    assert_eq!(content, "ayzd");
+}
+
+#[test]
+pub fn links_test() {
+    let mut arena = Arena::new(Token::token_vec_from_str("abc", |_, _| ()));
+    let b = arena.seek(1).unwrap();
+    assert_eq!(arena.content(b), Some("b"));
+    assert_eq!(arena.prev(b), arena.first());
+    replace_all(&mut arena, &[(0, 1, "")]);
+    assert_eq!(arena.first(), Some(b));
+    assert_eq!(arena.prev(b), None);
+    assert_eq!(arena.ids().count(), 2);
+}
+
+/// Checks `index` against `sigs`, the signatures it should have.
+fn check_index(index: &PositionIndex, sigs: &[u64]) {
+    assert_eq!(index.len(), sigs.len());
+    for bits in [1, 2, 6, u64::MAX] {
+        for at in 0..=sigs.len() {
+            let next = (at..sigs.len()).find(|&i| sigs[i] & bits != 0);
+            let prev = (0..at).rev().find(|&i| sigs[i] & bits != 0);
+            assert_eq!(index.next(at, bits), next);
+            assert_eq!(index.prev(at, bits), prev);
+            assert_eq!(index.get(at), sigs.get(at).copied());
+        }
+    }
+}
+
+#[test]
+pub fn index_test() {
+    let mut sigs = (0..50).map(|i| 1 << (i % 5)).collect::<Vec<u64>>();
+    let mut index = PositionIndex::new(sigs.iter().copied());
+    check_index(&index, &sigs);
+    let mut seed = 7u64;
+    for _ in 0..200 {
+        seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+        let start = (seed >> 33) as usize % (sigs.len() + 1);
+        let len = ((seed >> 20) as usize % 4).min(sigs.len() - start);
+        let new = (0..(seed >> 40) % 4).map(|i| 1 << ((seed >> (i * 3)) % 5));
+        let new = new.collect::<Vec<u64>>();
+        sigs.splice(start..start + len, new.iter().copied());
+        index.replace(start, len, new);
+        check_index(&index, &sigs);
+    }
+    assert!(index.entries.len() < 100);
+}
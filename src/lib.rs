@@ -1,3 +1,4 @@
+pub mod arena;
 pub mod meta;
 pub mod sequence;
 pub mod token;
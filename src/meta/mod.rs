@@ -1,6 +1,20 @@
 use super::*;
+use arena::{Arena, PositionIndex, TokenStore};
 use sequence::*;
+use std::borrow::Borrow;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::fmt::{Debug, Display, Formatter};
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read, Write};
+use std::iter::Peekable;
+use std::ops::Range;
+use std::panic;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
 use transform::*;
 
 #[cfg(test)]
@@ -8,13 +22,20 @@ mod tests;
 
 pub fn has_tag<'a>(tag: &'a str) -> impl Sequence<Vec<&str>> {
     FirstTokenSeq::new(move |tok: &Token<'_, Vec<&str>>| tok.data.contains(&tag))
+        .expecting(Expected::Tag(tag.to_owned()))
 }
 
 pub fn has_tag_owned<'a>(tag: String) -> impl Sequence<Vec<String>> {
+    let expected = Expected::Tag(tag.clone());
     FirstTokenSeq::new(move |tok: &Token<'_, Vec<String>>| tok.data.contains(&tag))
+        .expecting(expected)
 }
 
 pub fn raw_range<'a>(s: u32, e: u32) -> impl Sequence<Vec<String>> {
+    let expected = Expected::Range(
+        char::from_u32(s).unwrap_or_default(),
+        char::from_u32(e).unwrap_or_default(),
+    );
     FirstTokenSeq::new(move |tok: &Token<'_, Vec<String>>| {
         tok.content()
             .chars()
@@ -22,6 +43,7 @@ pub fn raw_range<'a>(s: u32, e: u32) -> impl Sequence<Vec<String>> {
             .is_some_and(|c| (s..=e).contains(&(c as u32)))
             && tok.content().len() == 1
     })
+    .expecting(expected)
 }
 
 pub fn tuck_tokens<'a>(text: &'a str) -> Vec<Token<Vec<&'a str>>> {
@@ -67,6 +89,18 @@ pub fn tuck_tokens<'a>(text: &'a str) -> Vec<Token<Vec<&'a str>>> {
         &mut tox,
     );
 
+    replace_all_matches_once(
+        &MultipleSeq::new(vec![
+            Box::new(RawSeq::new("\"")),
+            Box::new(RepeatedSeq::new(Box::new(FirstTokenSeq::new(|t| {
+                t.content() != "\""
+            })))),
+            Box::new(RawSeq::new("\"")),
+        ]),
+        &ShallowTransform { data: vec!["path"] },
+        &mut tox,
+    );
+
     replace_all_matches_once(
         &MultipleSeq::new(vec![
             Box::new(RawSeq::new("{")),
@@ -109,6 +143,22 @@ pub fn tuck_tokens<'a>(text: &'a str) -> Vec<Token<Vec<&'a str>>> {
         &mut tox,
     );
 
+    replace_all_matches_once(
+        &MultipleSeq::new(vec![Box::new(RawSeq::new("+")), Box::new(has_tag("word"))]),
+        &ShallowTransform {
+            data: vec!["add_tag"],
+        },
+        &mut tox,
+    );
+
+    replace_all_matches_once(
+        &MultipleSeq::new(vec![Box::new(RawSeq::new("$")), Box::new(has_tag("word"))]),
+        &ShallowTransform {
+            data: vec!["def_ref", "expr"],
+        },
+        &mut tox,
+    );
+
     let whitespace_seq = FirstTokenSeq::new(|tok: &Token<'_, Vec<&str>>| {
         tok.content()
             .chars()
@@ -152,6 +202,16 @@ pub fn tuck_tokens<'a>(text: &'a str) -> Vec<Token<Vec<&'a str>>> {
         Box::new(RawSeq::new(")")),
     ]);
 
+    let capture_seq = MultipleSeq::new(vec![
+        Box::new(has_tag("word")),
+        Box::new(RawSeq::new("@")),
+        Box::new(has_tag("expr")),
+    ]);
+
+    let mark_seq = MultipleSeq::new(vec![Box::new(RawSeq::new("@")), Box::new(has_tag("expr"))]);
+
+    let not_seq = MultipleSeq::new(vec![Box::new(RawSeq::new("!")), Box::new(has_tag("expr"))]);
+
     repeat_until_no_change(
         &vec![
             &|c| {
@@ -190,6 +250,33 @@ pub fn tuck_tokens<'a>(text: &'a str) -> Vec<Token<Vec<&'a str>>> {
                     c,
                 )
             },
+            &|c| {
+                replace_first_match(
+                    &capture_seq,
+                    &DeepTransform {
+                        data: vec!["capture", "expr"],
+                    },
+                    c,
+                )
+            },
+            &|c| {
+                replace_first_match(
+                    &mark_seq,
+                    &DeepTransform {
+                        data: vec!["mark", "expr"],
+                    },
+                    c,
+                )
+            },
+            &|c| {
+                replace_first_match(
+                    &not_seq,
+                    &DeepTransform {
+                        data: vec!["not", "expr"],
+                    },
+                    c,
+                )
+            },
             &|c| {
                 replace_first_match(
                     &choose_seq,
@@ -212,15 +299,41 @@ pub fn tuck_tokens<'a>(text: &'a str) -> Vec<Token<Vec<&'a str>>> {
         &mut tox,
     );
 
+    let tag_item = || {
+        Box::new(MultipleSeq::new(vec![
+            Box::new(has_tag("word")),
+            Box::new(OptionalSeq::new(Box::new(has_tag("parens")))),
+        ]))
+    };
+
+    let value_suffix = || {
+        Box::new(OptionalSeq::new(Box::new(MultipleSeq::new(vec![
+            Box::new(RawSeq::new("=")),
+            Box::new(RawSeq::new(">")),
+            Box::new(RawSeq::new("value")),
+            Box::new(has_tag("parens")),
+        ]))))
+    };
+
     let rep_deep_seq = MultipleSeq::new(vec![
         Box::new(has_tag("expr")),
         Box::new(RawSeq::new(":")),
         Box::new(MultipleSeq::new(vec![
             Box::new(RepeatedSeq::new(Box::new(MultipleSeq::new(vec![
-                Box::new(has_tag("word")),
+                tag_item(),
                 Box::new(RawSeq::new(",")),
             ])))),
-            Box::new(has_tag("word")),
+            tag_item(),
+            Box::new(OptionalSeq::new(Box::new(MultipleSeq::new(vec![
+                Box::new(RawSeq::new("[")),
+                Box::new(RepeatedSeq::new(Box::new(MultipleSeq::new(vec![
+                    Box::new(has_tag("word")),
+                    Box::new(RawSeq::new(",")),
+                ])))),
+                Box::new(has_tag("word")),
+                Box::new(RawSeq::new("]")),
+            ])))),
+            value_suffix(),
             Box::new(RawSeq::new(";")),
         ])),
     ]);
@@ -236,22 +349,162 @@ pub fn tuck_tokens<'a>(text: &'a str) -> Vec<Token<Vec<&'a str>>> {
         Box::new(RawSeq::new(".")),
         Box::new(MultipleSeq::new(vec![
             Box::new(RepeatedSeq::new(Box::new(MultipleSeq::new(vec![
-                Box::new(has_tag("word")),
+                tag_item(),
                 Box::new(RawSeq::new(",")),
             ])))),
-            Box::new(has_tag("word")),
+            tag_item(),
+            value_suffix(),
             Box::new(RawSeq::new(";")),
         ])),
     ]);
 
+    let retag_item = || {
+        Box::new(ChooseSeq::new(vec![
+            Box::new(has_tag("add_tag")),
+            Box::new(FirstTokenSeq::new(|t: &Token<'_, Vec<&str>>| {
+                t.data.contains(&"word") && t.content().starts_with('-')
+            })),
+        ]))
+    };
+
+    // Once a rule has started retagging, any word is taken as part of it, so
+    // that a missing `+` or `-` is reported where it's missing.
+    let rep_retag_seq = MultipleSeq::new(vec![
+        Box::new(has_tag("expr")),
+        retag_item(),
+        Box::new(RepeatedSeq::new(Box::new(ChooseSeq::new(vec![
+            retag_item(),
+            Box::new(has_tag("word")),
+        ])))),
+        Box::new(RawSeq::new(";")),
+    ]);
+
+    let rep_unwrap_seq = MultipleSeq::new(vec![
+        Box::new(has_tag("expr")),
+        Box::new(RawSeq::new("^")),
+        Box::new(OptionalSeq::new(Box::new(RawSeq::new("^")))),
+        Box::new(OptionalSeq::new(Box::new(has_tag("word")))),
+        Box::new(RawSeq::new(";")),
+    ]);
+
+    let rep_call_seq = MultipleSeq::new(vec![
+        Box::new(has_tag("expr")),
+        Box::new(RawSeq::new("=")),
+        Box::new(RawSeq::new(">")),
+        tag_item(),
+        Box::new(RepeatedSeq::new(Box::new(MultipleSeq::new(vec![
+            Box::new(RawSeq::new(",")),
+            tag_item(),
+        ])))),
+        Box::new(RawSeq::new(";")),
+    ]);
+
+    let split_part = || {
+        Box::new(MultipleSeq::new(vec![
+            Box::new(RawSeq::new("/")),
+            Box::new(RepeatedSeq::new(Box::new(ChooseSeq::new(vec![
+                Box::new(has_tag("word")),
+                Box::new(RawSeq::new(",")),
+            ])))),
+        ]))
+    };
+
+    let rep_split_seq = MultipleSeq::new(vec![
+        Box::new(has_tag("expr")),
+        split_part(),
+        split_part(),
+        Box::new(RepeatedSeq::new(split_part())),
+        Box::new(RawSeq::new(";")),
+    ]);
+
     let rep_once_seq = MultipleSeq::new(vec![Box::new(RawSeq::new("%")), Box::new(has_tag("rep"))]);
 
+    let operator = || {
+        Box::new(ChooseSeq::new(vec![
+            Box::new(has_tag("raw")),
+            Box::new(has_tag("word")),
+        ]))
+    };
+    let prec_level_seq = MultipleSeq::new(vec![
+        Box::new(ChooseSeq::new(vec![
+            Box::new(RawSeq::new("left")),
+            Box::new(RawSeq::new("right")),
+            Box::new(RawSeq::new("prefix")),
+        ])),
+        operator(),
+        Box::new(RepeatedSeq::new(operator())),
+        Box::new(RawSeq::new(";")),
+    ]);
+
+    let precedence_seq = MultipleSeq::new(vec![
+        Box::new(RawSeq::new("precedence")),
+        Box::new(has_tag("word")),
+        Box::new(RawSeq::new("{")),
+        Box::new(RepeatedSeq::new(Box::new(has_tag("prec_level")))),
+        Box::new(RawSeq::new("}")),
+    ]);
+
+    let rep_reverse_seq =
+        |inner| MultipleSeq::new(vec![Box::new(RawSeq::new("<")), Box::new(has_tag(inner))]);
+
     let rep_branch_seq = MultipleSeq::new(vec![
         Box::new(RawSeq::new("{")),
         Box::new(RepeatedSeq::new(Box::new(has_tag("no_once")))),
         Box::new(RawSeq::new("}")),
     ]);
 
+    let in_seq = MultipleSeq::new(vec![
+        Box::new(RawSeq::new("in")),
+        Box::new(has_tag("word")),
+        Box::new(RepeatedSeq::new(Box::new(MultipleSeq::new(vec![
+            Box::new(RawSeq::new(",")),
+            Box::new(has_tag("word")),
+        ])))),
+        Box::new(has_tag("rep_branch")),
+    ]);
+
+    let layout_seq = MultipleSeq::new(vec![
+        Box::new(RawSeq::new("layout")),
+        Box::new(has_tag("word")),
+        Box::new(RawSeq::new(";")),
+    ]);
+
+    let import_seq = MultipleSeq::new(vec![
+        Box::new(RawSeq::new("import")),
+        Box::new(OptionalSeq::new(Box::new(MultipleSeq::new(vec![
+            Box::new(RepeatedSeq::new(Box::new(MultipleSeq::new(vec![
+                Box::new(has_tag("word")),
+                Box::new(RawSeq::new(",")),
+            ])))),
+            Box::new(has_tag("word")),
+            Box::new(RawSeq::new("from")),
+        ])))),
+        Box::new(has_tag("path")),
+        Box::new(RawSeq::new(";")),
+    ]);
+
+    let def_seq = MultipleSeq::new(vec![
+        Box::new(RawSeq::new("def")),
+        Box::new(has_tag("word")),
+        Box::new(RawSeq::new("=")),
+        Box::new(has_tag("expr")),
+        Box::new(RawSeq::new(";")),
+    ]);
+
+    let boundary_seq = MultipleSeq::new(vec![
+        Box::new(RawSeq::new("boundary")),
+        Box::new(has_tag("expr")),
+        Box::new(RawSeq::new(";")),
+    ]);
+
+    let recover_seq = MultipleSeq::new(vec![
+        Box::new(RawSeq::new("recover")),
+        Box::new(has_tag("word")),
+        Box::new(RawSeq::new("until")),
+        Box::new(has_tag("expr")),
+        Box::new(RawSeq::new(";")),
+    ]);
+
     repeat_until_no_change(
         &vec![
             &|c| {
@@ -281,6 +534,78 @@ pub fn tuck_tokens<'a>(text: &'a str) -> Vec<Token<Vec<&'a str>>> {
                     c,
                 )
             },
+            &|c| {
+                replace_all_matches_once(
+                    &rep_retag_seq,
+                    &DeepTransform {
+                        data: vec!["rep_leaf", "rep_retag", "rep", "no_once"],
+                    },
+                    c,
+                )
+            },
+            &|c| {
+                replace_all_matches_once(
+                    &rep_unwrap_seq,
+                    &DeepTransform {
+                        data: vec!["rep_leaf", "rep_unwrap", "rep", "no_once"],
+                    },
+                    c,
+                )
+            },
+            &|c| {
+                replace_all_matches_once(
+                    &rep_split_seq,
+                    &DeepTransform {
+                        data: vec!["rep_leaf", "rep_split", "rep", "no_once"],
+                    },
+                    c,
+                )
+            },
+            &|c| {
+                replace_all_matches_once(
+                    &rep_call_seq,
+                    &DeepTransform {
+                        data: vec!["rep_call", "rep", "no_once"],
+                    },
+                    c,
+                )
+            },
+            &|c| {
+                replace_all_matches_once(
+                    &prec_level_seq,
+                    &DeepTransform {
+                        data: vec!["prec_level"],
+                    },
+                    c,
+                )
+            },
+            &|c| {
+                replace_all_matches_once(
+                    &precedence_seq,
+                    &DeepTransform {
+                        data: vec!["precedence", "rep", "no_once"],
+                    },
+                    c,
+                )
+            },
+            &|c| {
+                replace_all_matches_once(
+                    &rep_reverse_seq("no_once"),
+                    &DeepTransform {
+                        data: vec!["reverse", "rep", "no_once"],
+                    },
+                    c,
+                )
+            },
+            &|c| {
+                replace_all_matches_once(
+                    &rep_reverse_seq("rep"),
+                    &DeepTransform {
+                        data: vec!["reverse", "rep"],
+                    },
+                    c,
+                )
+            },
             &|c| {
                 replace_all_matches_once(
                     &rep_once_seq,
@@ -299,6 +624,52 @@ pub fn tuck_tokens<'a>(text: &'a str) -> Vec<Token<Vec<&'a str>>> {
                     c,
                 )
             },
+            &|c| {
+                replace_all_matches_once(
+                    &in_seq,
+                    &DeepTransform {
+                        data: vec!["in", "rep", "no_once"],
+                    },
+                    c,
+                )
+            },
+            &|c| {
+                replace_all_matches_once(
+                    &layout_seq,
+                    &DeepTransform {
+                        data: vec!["layout"],
+                    },
+                    c,
+                )
+            },
+            &|c| {
+                replace_all_matches_once(
+                    &import_seq,
+                    &DeepTransform {
+                        data: vec!["import"],
+                    },
+                    c,
+                )
+            },
+            &|c| replace_all_matches_once(&def_seq, &DeepTransform { data: vec!["def"] }, c),
+            &|c| {
+                replace_all_matches_once(
+                    &boundary_seq,
+                    &DeepTransform {
+                        data: vec!["boundary"],
+                    },
+                    c,
+                )
+            },
+            &|c| {
+                replace_all_matches_once(
+                    &recover_seq,
+                    &DeepTransform {
+                        data: vec!["recover"],
+                    },
+                    c,
+                )
+            },
         ],
         &mut tox,
     );
@@ -306,95 +677,619 @@ pub fn tuck_tokens<'a>(text: &'a str) -> Vec<Token<Vec<&'a str>>> {
     tox
 }
 
-pub fn create_program<'a>(tokens: Vec<Token<'a, Vec<&'a str>>>) -> Option<SeqProg> {
-    let mut prog = SeqProg { reps: vec![] };
+pub fn create_program<'a>(
+    tokens: Vec<Token<'a, Vec<&'a str>>>,
+    registry: &TransformRegistry,
+) -> Result<SeqProg, CompileError> {
+    create_program_in(tokens, registry, &mut Importer::new(&MemoryResolver::new()))
+}
+
+fn create_program_in<'a>(
+    tokens: Vec<Token<'a, Vec<&'a str>>>,
+    registry: &TransformRegistry,
+    importer: &mut Importer,
+) -> Result<SeqProg, CompileError> {
+    let mut prog = SeqProg {
+        reps: vec![],
+        recoveries: vec![],
+        layout: None,
+        boundary: None,
+        defs: HashMap::new(),
+    };
+
+    // Imports come first, so that definitions can use what they bring in.
+    let mut imported = HashMap::new();
+    for (i, token) in tokens.iter().enumerate() {
+        if token.data.contains(&"import") {
+            let sub = importer.import(token, registry, &mut prog.defs)?;
+            imported.insert(i, sub);
+        }
+    }
+
+    define_all(&tokens, &mut prog)?;
+
+    for (i, token) in tokens.into_iter().enumerate() {
+        if let Some(sub) = imported.remove(&i).flatten() {
+            prog.reps.extend(sub.reps);
+            prog.recoveries.extend(sub.recoveries);
+            prog.layout = prog.layout.or(sub.layout);
+            prog.boundary = prog.boundary.or(sub.boundary);
+        } else if token.data.contains(&"rep") {
+            prog.reps.push(eval_rep(&token, &prog, registry)?);
+        } else if token.data.contains(&"recover") {
+            let malformed = || CompileError::new(CompileErrorKind::Malformed, &token);
+            prog.recoveries.push(Recovery {
+                tag: token
+                    .nth_child(1)
+                    .ok_or_else(malformed)?
+                    .content()
+                    .to_owned(),
+                until: token
+                    .nth_child(3)
+                    .and_then(|t| eval_sequence_in(t, &prog.defs))
+                    .ok_or_else(malformed)?,
+            });
+        } else if token.data.contains(&"boundary") {
+            prog.boundary = Some(
+                token
+                    .nth_child(1)
+                    .and_then(|t| eval_sequence_in(t, &prog.defs))
+                    .ok_or_else(|| CompileError::new(CompileErrorKind::Malformed, &token))?,
+            );
+        } else if token.data.contains(&"layout") {
+            prog.layout = match token.nth_child(1).map(|t| t.content()) {
+                Some("indent") => Some(Layout::Indent),
+                _ => return Err(CompileError::new(CompileErrorKind::Malformed, &token)),
+            };
+        }
+    }
+
+    Ok(prog)
+}
+
+/// Named sequences, declared with `def name = rule;` and used with `$name`.
+pub type Definitions = HashMap<String, Arc<SyncSequence<Vec<String>>>>;
 
+/// Compiles the `def` declarations among `tokens` into `prog.defs`, after
+/// checking that every `$name` has a definition. A definition can use ones
+/// declared after it, as long as none of them end up using themselves.
+fn define_all(tokens: &[Token<Vec<&str>>], prog: &mut SeqProg) -> Result<(), CompileError> {
+    let mut names = vec![];
+    let mut bodies = HashMap::new();
+    for token in tokens.iter().filter(|t| t.data.contains(&"def")) {
+        let malformed = || CompileError::new(CompileErrorKind::Malformed, token);
+        let name = token.nth_child(1).ok_or_else(malformed)?.content();
+        if bodies
+            .insert(name, token.nth_child(3).ok_or_else(malformed)?)
+            .is_some()
+            || prog.defs.contains_key(name)
+        {
+            let kind = CompileErrorKind::Redefined(name.to_owned());
+            return Err(CompileError::new(kind, token));
+        }
+        names.push(name);
+    }
     for token in tokens {
-        if token.data.contains(&"rep") {
-            prog.reps.push(eval_rep(&token, &prog)?);
+        for used in def_refs(token) {
+            let name = &used.content()[1..];
+            if !bodies.contains_key(name) && !prog.defs.contains_key(name) {
+                let kind = CompileErrorKind::UndefinedName(name.to_owned());
+                return Err(CompileError::new(kind, used));
+            }
         }
     }
+    for name in names {
+        define(name, &bodies, &mut prog.defs, &mut vec![])?;
+    }
+    Ok(())
+}
+
+/// Compiles the definition of `name`, and the ones it uses first. `using` is
+/// the definitions that are waiting on this one.
+fn define<'t>(
+    name: &'t str,
+    bodies: &HashMap<&'t str, &'t Token<Vec<&str>>>,
+    defs: &mut Definitions,
+    using: &mut Vec<&'t str>,
+) -> Result<(), CompileError> {
+    if defs.contains_key(name) {
+        return Ok(());
+    }
+    let body = bodies[name];
+    if let Some(i) = using.iter().position(|&n| n == name) {
+        let cycle = using[i..].iter().map(|n| n.to_string()).collect();
+        return Err(CompileError::new(
+            CompileErrorKind::DefinitionCycle(cycle),
+            body,
+        ));
+    }
+    using.push(name);
+    for used in def_refs(body) {
+        define(&used.content()[1..], bodies, defs, using)?;
+    }
+    using.pop();
+    let seq = eval_sequence_in(body, defs)
+        .ok_or_else(|| CompileError::new(CompileErrorKind::Malformed, body))?;
+    defs.insert(name.to_owned(), Arc::from(seq));
+    Ok(())
+}
 
-    Some(prog)
+/// Every `$name` in `token`.
+fn def_refs<'t, 'a>(token: &'t Token<'a, Vec<&'a str>>) -> Vec<&'t Token<'a, Vec<&'a str>>> {
+    match token.children() {
+        _ if token.data.contains(&"def_ref") => vec![token],
+        Some(children) => children.iter().flat_map(def_refs).collect(),
+        None => vec![],
+    }
 }
 
-pub fn eval_rep(token: &Token<Vec<&str>>, prog: &SeqProg) -> Option<RepTree> {
+pub fn eval_rep(
+    token: &Token<Vec<&str>>,
+    prog: &SeqProg,
+    registry: &TransformRegistry,
+) -> Result<RepTree, CompileError> {
+    let malformed = || CompileError::new(CompileErrorKind::Malformed, token);
     if token.data.contains(&"once") {
-        Some(RepTree::Once(Box::new(eval_rep(
-            token.nth_child(1)?,
+        let inner = token.nth_child(1).ok_or_else(malformed)?;
+        if inner.data.contains(&"rep_branch") && uses_modes(inner) {
+            return Ok(RepTree::Lexer(eval_lexer(inner, prog, registry)?));
+        }
+        Ok(RepTree::Once(Box::new(eval_rep(inner, prog, registry)?)))
+    } else if token.data.contains(&"in") {
+        Err(CompileError::new(CompileErrorKind::ModeOutsidePass, token))
+    } else if token.data.contains(&"precedence") {
+        let children = token.children().ok_or_else(malformed)?;
+        let levels = children[3..children.len() - 1]
+            .iter()
+            .map(|level| {
+                let parts = level.children().ok_or_else(malformed)?;
+                Ok(PrecedenceLevel {
+                    fixity: match parts[0].content() {
+                        "left" => Fixity::Left,
+                        "right" => Fixity::Right,
+                        _ => Fixity::Prefix,
+                    },
+                    operators: parts[1..parts.len() - 1]
+                        .iter()
+                        .map(|op| match op.data.contains(&"raw") {
+                            true => {
+                                Operator::Raw(op.content()[1..op.content().len() - 1].to_owned())
+                            }
+                            false => Operator::Tag(op.content().to_owned()),
+                        })
+                        .collect(),
+                })
+            })
+            .collect::<Result<Vec<_>, CompileError>>()?;
+        Ok(RepTree::Precedence(
+            Precedence::new(children[1].content().to_owned(), levels),
+            RuleSource::new(token),
+        ))
+    } else if token.data.contains(&"reverse") {
+        Ok(RepTree::Reverse(Box::new(eval_rep(
+            token.nth_child(1).ok_or_else(malformed)?,
             prog,
+            registry,
         )?)))
-    } else if token.data.contains(&"rep_leaf") {
-        if let TokenType::Branch(children) = &token.t_type {
-            if token.data.contains(&"rep_remove") {
-                if let Some(seq) = eval_sequence(&children[0]) {
-                    return Some(RepTree::Leaf(seq, Box::new(RemoveTransform {})));
-                } else {
-                    return None;
+    } else if token.data.contains(&"rep_call") {
+        let children = token.children().ok_or_else(malformed)?;
+        let mut items = children[3..children.len() - 1]
+            .iter()
+            .filter(|c| c.content() != ",")
+            .peekable();
+        let name = items.next().ok_or_else(malformed)?;
+        let args = items.next_if(|next| next.data.contains(&"parens"));
+        let tags = items
+            .map(|t| match t.data.contains(&"word") {
+                true => Ok(t.content().to_owned()),
+                false => Err(malformed()),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let unknown = || {
+            CompileError::new(
+                CompileErrorKind::UnknownTransform(name.content().to_owned()),
+                name,
+            )
+        };
+        let transform: Box<SyncTransform<Vec<String>>> = match (name.content(), args) {
+            ("insert", None) => Box::new(InsertTransform {
+                text: None,
+                data: tags,
+            }),
+            ("insert", Some(args)) => {
+                let arg = args.nth_child(1).ok_or_else(malformed)?;
+                if !arg.data.contains(&"raw") {
+                    return Err(malformed());
                 }
+                Box::new(InsertTransform {
+                    text: Some(arg.content()[1..arg.content().len() - 1].to_owned()),
+                    data: tags,
+                })
             }
-
-            let mut new_tag_tokens = vec![];
-            for i in 0usize.. {
-                let paren_index = 2 + 2 * i;
-                if paren_index >= children.len() {
-                    break;
-                }
-                new_tag_tokens.push(&children[paren_index]);
-            }
-            if let Some(seq) = eval_sequence(&children[0]) {
-                if token.data.contains(&"rep_deep") {
-                    Some(RepTree::Leaf(
-                        seq,
-                        Box::new(DeepTransform {
-                            data: new_tag_tokens
-                                .iter()
-                                .map(|t| t.content().to_owned())
-                                .collect(),
-                        }),
-                    ))
-                } else if token.data.contains(&"rep_shallow") {
-                    Some(RepTree::Leaf(
-                        seq,
-                        Box::new(ShallowTransform {
-                            data: new_tag_tokens
-                                .iter()
-                                .map(|t| t.content().to_owned())
-                                .collect(),
-                        }),
-                    ))
-                } else {
-                    None
-                }
-            } else {
-                None
+            ("value", args) if tags.is_empty() => {
+                let (name, convert) = eval_converter(args, token, registry)?;
+                Box::new(ValueTransform {
+                    inner: None,
+                    name,
+                    convert,
+                })
             }
-        } else {
-            None
+            (_, Some(_)) => return Err(unknown()),
+            (_, None) if !tags.is_empty() => return Err(malformed()),
+            (name, None) => Box::new(registry.get(name).ok_or_else(unknown)?),
+        };
+        let mut seq = eval_sequence_in(token.nth_child(0).ok_or_else(malformed)?, &prog.defs)
+            .ok_or_else(malformed)?;
+        if !matches!(name.content(), "insert" | "value") {
+            // The other transforms only change the tokens they're given, so
+            // an empty match wouldn't change anything.
+            seq = guarded(seq, |_, m| m.len > 0);
+        } else if name.content() == "value" {
+            // Only match while there's something left to convert.
+            seq = guarded(seq, |tox, m| {
+                m.marked()
+                    .iter()
+                    .any(|&i| tox.get(i).is_some_and(|t| t.value.is_none()))
+            });
         }
-    } else if token.data.contains(&"rep_branch") {
-        if let TokenType::Branch(children) = &token.t_type {
-            Some(RepTree::Branch(
-                children[1..children.len() - 1]
-                    .iter()
-                    .map(|t| match eval_rep(t, prog) {
-                        Some(rt) => Some(rt),
-                        None => {
-                            return None;
-                        }
-                    })
-                    .map(|o| o.unwrap())
-                    .collect(),
+        Ok(RepTree::Leaf(seq, transform, RuleSource::new(token)))
+    } else if token.data.contains(&"rep_leaf") {
+        if mode_switch(token)?.is_some() {
+            return Err(CompileError::new(CompileErrorKind::ModeOutsidePass, token));
+        }
+        eval_rule(token, prog, registry)
+    } else if token.data.contains(&"rep_branch") {
+        let children = token.children().ok_or_else(malformed)?;
+        Ok(RepTree::Branch(
+            children[1..children.len() - 1]
+                .iter()
+                .map(|t| eval_rep(t, prog, registry))
+                .collect::<Result<_, _>>()?,
+        ))
+    } else {
+        Err(malformed())
+    }
+}
+
+/// Compiles a rule that rewrites the tokens it matches, other than with
+/// `=> name`.
+fn eval_rule(
+    token: &Token<Vec<&str>>,
+    prog: &SeqProg,
+    registry: &TransformRegistry,
+) -> Result<RepTree, CompileError> {
+    let malformed = || CompileError::new(CompileErrorKind::Malformed, token);
+    if token.data.contains(&"rep_retag") {
+        let children = token.children().ok_or_else(malformed)?;
+        if let Some(stray) = children[1..children.len() - 1]
+            .iter()
+            .find(|c| !c.data.contains(&"add_tag") && !c.content().starts_with('-'))
+        {
+            let kind = CompileErrorKind::NotATagChange(stray.content().to_owned());
+            return Err(CompileError::new(kind, stray));
+        }
+    }
+    let leaf = eval_leaf(token, &prog.defs).ok_or_else(malformed)?;
+    let children = token.children().ok_or_else(malformed)?;
+    match (children.iter().position(|c| c.content() == "="), leaf) {
+        (Some(eq), RepTree::Leaf(seq, inner, source)) => {
+            let (name, convert) = eval_converter(children.get(eq + 3), token, registry)?;
+            Ok(RepTree::Leaf(
+                seq,
+                Box::new(ValueTransform {
+                    inner: Some(inner),
+                    name,
+                    convert,
+                }),
+                source,
             ))
+        }
+        (_, leaf) => Ok(leaf),
+    }
+}
+
+/// Whether the `{ }` group `token` has any `in` groups or rules that switch
+/// modes, making a `%` pass of it a `Lexer`.
+fn uses_modes(token: &Token<Vec<&str>>) -> bool {
+    token.children().is_some_and(|children| {
+        children.iter().any(|child| {
+            child.data.contains(&"in") || mode_switch(child).is_ok_and(|s| s.is_some())
+        })
+    })
+}
+
+/// Compiles the rules of the `{ }` group `token` into a `Lexer`, along with
+/// the modes each of them is active in.
+fn eval_lexer(
+    token: &Token<Vec<&str>>,
+    prog: &SeqProg,
+    registry: &TransformRegistry,
+) -> Result<Lexer, CompileError> {
+    let mut lexer = Lexer { rules: vec![] };
+    add_lex_rules(token, &[MAIN_MODE], &mut lexer, prog, registry)?;
+    Ok(lexer)
+}
+
+fn add_lex_rules(
+    token: &Token<Vec<&str>>,
+    modes: &[&str],
+    lexer: &mut Lexer,
+    prog: &SeqProg,
+    registry: &TransformRegistry,
+) -> Result<(), CompileError> {
+    let malformed = || CompileError::new(CompileErrorKind::Malformed, token);
+    let children = token.children().ok_or_else(malformed)?;
+    for child in &children[1..children.len() - 1] {
+        if child.data.contains(&"in") {
+            let parts = child.children().ok_or_else(malformed)?;
+            let modes = parts[1..parts.len() - 1]
+                .iter()
+                .filter(|p| p.data.contains(&"word"))
+                .map(|p| p.content())
+                .collect::<Vec<_>>();
+            add_lex_rules(&parts[parts.len() - 1], &modes, lexer, prog, registry)?;
+            continue;
+        }
+        let switch = mode_switch(child)?;
+        let rule = match child.data.contains(&"rep_leaf") {
+            // `eval_rep` won't take a rule that switches modes.
+            true => eval_rule(child, prog, registry)?,
+            false => eval_rep(child, prog, registry)?,
+        };
+        let RepTree::Leaf(seq, trans, source) = rule else {
+            return Err(CompileError::new(CompileErrorKind::NotALexerRule, child));
+        };
+        lexer.rules.push(LexRule {
+            seq,
+            trans,
+            source,
+            modes: modes.iter().map(|m| m.to_string()).collect(),
+            switch,
+        });
+    }
+    Ok(())
+}
+
+/// Reads the `push(mode)` or `pop(mode)` among the tags of a `.` or `:` rule.
+fn mode_switch(token: &Token<Vec<&str>>) -> Result<Option<ModeSwitch>, CompileError> {
+    if !token.data.contains(&"rep_deep") && !token.data.contains(&"rep_shallow") {
+        return Ok(None);
+    }
+    let malformed = || CompileError::new(CompileErrorKind::Malformed, token);
+    let children = token.children().ok_or_else(malformed)?;
+    let tags_end = children
+        .iter()
+        .position(|c| ["[", "=", ";"].contains(&c.content()))
+        .ok_or_else(malformed)?;
+    let mut switch = None;
+    for pair in children[2..tags_end].windows(2) {
+        if !pair[1].data.contains(&"parens") {
+            continue;
+        }
+        let mode = pair[1]
+            .nth_child(1)
+            .ok_or_else(malformed)?
+            .content()
+            .to_owned();
+        let found = match pair[0].content() {
+            "push" => ModeSwitch::Push(mode),
+            "pop" => ModeSwitch::Pop(mode),
+            _ => continue,
+        };
+        if switch.replace(found).is_some() {
+            return Err(malformed());
+        }
+    }
+    Ok(switch)
+}
+
+/// Looks up the converter named in the parentheses of `value(name)`.
+fn eval_converter(
+    args: Option<&Token<Vec<&str>>>,
+    token: &Token<Vec<&str>>,
+    registry: &TransformRegistry,
+) -> Result<(String, Arc<Converter>), CompileError> {
+    let name = args
+        .and_then(|a| a.nth_child(1))
+        .filter(|n| n.data.contains(&"word"))
+        .ok_or_else(|| CompileError::new(CompileErrorKind::Malformed, token))?;
+    let convert = registry.converter(name.content()).ok_or_else(|| {
+        CompileError::new(
+            CompileErrorKind::UnknownConverter(name.content().to_owned()),
+            name,
+        )
+    })?;
+    Ok((name.content().to_owned(), convert))
+}
+
+fn eval_leaf(token: &Token<Vec<&str>>, defs: &Definitions) -> Option<RepTree> {
+    if let TokenType::Branch(children) = &token.t_type {
+        if token.data.contains(&"rep_remove") {
+            if let Some(seq) = eval_sequence_in(&children[0], defs) {
+                // Removing nothing wouldn't change anything.
+                return Some(RepTree::Leaf(
+                    guarded(seq, |_, m| m.len > 0),
+                    Box::new(RemoveTransform {}),
+                    RuleSource::new(token),
+                ));
+            } else {
+                return None;
+            }
+        }
+
+        if token.data.contains(&"rep_retag") {
+            let mut retag = RetagTransform {
+                add: vec![],
+                remove: vec![],
+            };
+            for item in &children[1..children.len() - 1] {
+                if item.data.contains(&"add_tag") {
+                    retag.add.push(item.content()[1..].to_owned());
+                } else {
+                    retag
+                        .remove
+                        .push(item.content().strip_prefix('-')?.to_owned());
+                }
+            }
+            let guard = retag.clone();
+            return Some(RepTree::Leaf(
+                guarded(eval_sequence_in(&children[0], defs)?, move |tox, m| {
+                    m.marked()
+                        .iter()
+                        .any(|&i| tox.get(i).is_some_and(|t| guard.would_change(&t.data)))
+                }),
+                Box::new(retag),
+                RuleSource::new(token),
+            ));
+        }
+
+        if token.data.contains(&"rep_unwrap") {
+            let carets = children.iter().filter(|c| c.content() == "^").count();
+            let unwrap = UnwrapTransform {
+                child_tag: children[1..]
+                    .iter()
+                    .find(|c| c.data.contains(&"word"))
+                    .map(|c| c.content().to_owned()),
+                merge_tags: carets > 1,
+            };
+            let seq = eval_sequence_in(&children[0], defs)?;
+            if unwrap.child_tag.is_none() && !unwrap.merge_tags {
+                return Some(RepTree::Leaf(
+                    guarded(seq, |tox, m| {
+                        tox[..m.len].iter().any(|t| t.children().is_some())
+                    }),
+                    Box::new(FlattenTransform {}),
+                    RuleSource::new(token),
+                ));
+            }
+            let guard = unwrap.clone();
+            return Some(RepTree::Leaf(
+                guarded(seq, move |tox, m| {
+                    tox[..m.len].iter().any(|t| guard.unwraps(t))
+                }),
+                Box::new(unwrap),
+                RuleSource::new(token),
+            ));
+        }
+
+        if token.data.contains(&"rep_split") {
+            let mut split = SplitTransform {
+                at: vec![],
+                data: vec![],
+            };
+            for part in children[2..children.len() - 1].split(|c| c.content() == "/") {
+                let mut words = part.iter().filter(|c| c.content() != ",");
+                if !split.data.is_empty() {
+                    let at = words.next()?.content().parse::<usize>().ok()?;
+                    if at == 0 || split.at.last().is_some_and(|&prev| at <= prev) {
+                        return None;
+                    }
+                    split.at.push(at);
+                }
+                split
+                    .data
+                    .push(words.map(|w| w.content().to_owned()).collect());
+            }
+            let guard = split.clone();
+            return Some(RepTree::Leaf(
+                guarded(eval_sequence_in(&children[0], defs)?, move |tox, m| {
+                    m.marked()
+                        .iter()
+                        .any(|&i| tox.get(i).is_some_and(|t| guard.splits(t)))
+                }),
+                Box::new(split),
+                RuleSource::new(token),
+            ));
+        }
+
+        let tags_end = children
+            .iter()
+            .position(|c| ["[", "=", ";"].contains(&c.content()))?;
+        let captures = capture_names(&children[0]);
+        let mut tags = vec![];
+        let mut inherit = vec![];
+        let mut items = children[2..tags_end]
+            .iter()
+            .filter(|c| c.content() != ",")
+            .peekable();
+        while let Some(item) = items.next() {
+            match items.next_if(|next| next.data.contains(&"parens")) {
+                Some(args) if item.content() == "inherit" => {
+                    let arg = args.nth_child(1)?.content();
+                    inherit.push(match eval_selector(arg, &captures) {
+                        Some(selector) => InheritFrom::Part(selector),
+                        None => InheritFrom::Tagged(arg.to_owned()),
+                    });
+                }
+                // Read by `mode_switch`.
+                Some(_) if ["push", "pop"].contains(&item.content()) => {}
+                Some(_) => return None,
+                None => tags.push(item.content().to_owned()),
+            }
+        }
+
+        let seq = eval_sequence_in(&children[0], defs)?;
+        let transform: Box<SyncTransform<Vec<String>>> =
+            if token.data.contains(&"rep_deep") && children[tags_end].content() == "[" {
+                let select_end = children.iter().position(|c| c.content() == "]")?;
+                let select = children[tags_end + 1..select_end]
+                    .iter()
+                    .step_by(2)
+                    .map(|t| eval_selector(t.content(), &captures))
+                    .collect::<Option<Vec<_>>>()?;
+                Box::new(SelectTransform { data: tags, select })
+            } else if token.data.contains(&"rep_deep") {
+                Box::new(DeepTransform { data: tags })
+            } else if token.data.contains(&"rep_shallow") {
+                Box::new(ShallowTransform { data: tags })
+            } else {
+                return None;
+            };
+
+        if inherit.is_empty() {
+            Some(RepTree::Leaf(seq, transform, RuleSource::new(token)))
         } else {
-            None
+            Some(RepTree::Leaf(
+                seq,
+                Box::new(InheritTransform {
+                    inner: transform,
+                    from: inherit,
+                }),
+                RuleSource::new(token),
+            ))
         }
     } else {
         None
     }
 }
 
-pub fn eval_sequence(token: &Token<Vec<&str>>) -> Option<Box<dyn Sequence<Vec<String>>>> {
+/// Reads a number as the index of an element, or a name as a capture.
+fn eval_selector(text: &str, captures: &[&str]) -> Option<Selector> {
+    match text.parse::<usize>() {
+        Ok(i) => Some(Selector::Element(i)),
+        Err(_) if captures.contains(&text) => Some(Selector::Capture(text.to_owned())),
+        Err(_) => None,
+    }
+}
+
+/// `seq`, but only matching where `guard` accepts the match.
+fn guarded(
+    seq: Box<SyncSequence<Vec<String>>>,
+    guard: impl Fn(&[&Token<Vec<String>>], &Match) -> bool + Send + Sync + 'static,
+) -> Box<SyncSequence<Vec<String>>> {
+    Box::new(GuardSeq::of(seq, Box::new(guard)))
+}
+
+pub fn eval_sequence(token: &Token<Vec<&str>>) -> Option<Box<SyncSequence<Vec<String>>>> {
+    eval_sequence_in(token, &Definitions::new())
+}
+
+/// Like `eval_sequence`, but `$name` refers to the definition of `name` in
+/// `defs`.
+pub fn eval_sequence_in(
+    token: &Token<Vec<&str>>,
+    defs: &Definitions,
+) -> Option<Box<SyncSequence<Vec<String>>>> {
     if token.data.contains(&"mult") {
         if let TokenType::Branch(children) = &token.t_type {
             let mut paren_exprs = vec![];
@@ -405,11 +1300,11 @@ pub fn eval_sequence(token: &Token<Vec<&str>>) -> Option<Box<dyn Sequence<Vec<St
                 }
                 paren_exprs.push(&children[paren_index]);
             }
-            Some(Box::new(MultipleSeq::new(
+            Some(Box::new(MultipleSeq::of(
                 paren_exprs
                     .iter()
                     .map(|e| {
-                        Some(match eval_sequence(&e) {
+                        Some(match eval_sequence_in(&e, defs) {
                             Some(ds) => ds,
                             _ => {
                                 return None;
@@ -432,11 +1327,11 @@ pub fn eval_sequence(token: &Token<Vec<&str>>) -> Option<Box<dyn Sequence<Vec<St
                 }
                 paren_exprs.push(&children[paren_index]);
             }
-            Some(Box::new(ChooseSeq::new(
+            Some(Box::new(ChooseSeq::of(
                 paren_exprs
                     .iter()
                     .map(|e| {
-                        Some(match eval_sequence(&e) {
+                        Some(match eval_sequence_in(&e, defs) {
                             Some(ds) => ds,
                             _ => {
                                 return None;
@@ -451,27 +1346,60 @@ pub fn eval_sequence(token: &Token<Vec<&str>>) -> Option<Box<dyn Sequence<Vec<St
         }
     } else if token.data.contains(&"parens") {
         if let TokenType::Branch(children) = &token.t_type {
-            eval_sequence(children.get(1)?)
+            eval_sequence_in(children.get(1)?, defs)
+        } else {
+            None
+        }
+    } else if token.data.contains(&"capture") {
+        if let TokenType::Branch(children) = &token.t_type {
+            Some(Box::new(CaptureSeq::of(
+                Some(children.first()?.content().to_owned()),
+                eval_sequence_in(children.get(2)?, defs)?,
+            )))
+        } else {
+            None
+        }
+    } else if token.data.contains(&"not") {
+        if let TokenType::Branch(children) = &token.t_type {
+            Some(Box::new(NotSeq::of(eval_sequence_in(
+                children.get(1)?,
+                defs,
+            )?)))
+        } else {
+            None
+        }
+    } else if token.data.contains(&"mark") {
+        if let TokenType::Branch(children) = &token.t_type {
+            Some(Box::new(CaptureSeq::of(
+                None,
+                eval_sequence_in(children.get(1)?, defs)?,
+            )))
         } else {
             None
         }
     } else if token.data.contains(&"opt") {
         if let TokenType::Branch(children) = &token.t_type {
-            Some(Box::new(OptionalSeq::new(eval_sequence(children.get(0)?)?)))
+            Some(Box::new(OptionalSeq::of(eval_sequence_in(
+                children.get(0)?,
+                defs,
+            )?)))
         } else {
             None
         }
     } else if token.data.contains(&"repeat") {
         if let TokenType::Branch(children) = &token.t_type {
-            Some(Box::new(RepeatedSeq::new(eval_sequence(children.get(0)?)?)))
+            Some(Box::new(RepeatedSeq::of(eval_sequence_in(
+                children.get(0)?,
+                defs,
+            )?)))
         } else {
             None
         }
     } else if token.data.contains(&"one_or_more") {
         if let TokenType::Branch(children) = &token.t_type {
-            Some(Box::new(MultipleSeq::new(vec![
-                eval_sequence(children.get(0)?)?,
-                Box::new(RepeatedSeq::new(eval_sequence(children.get(0)?)?)),
+            Some(Box::new(MultipleSeq::of(vec![
+                eval_sequence_in(children.get(0)?, defs)?,
+                Box::new(RepeatedSeq::of(eval_sequence_in(children.get(0)?, defs)?)),
             ])))
         } else {
             None
@@ -481,10 +1409,10 @@ pub fn eval_sequence(token: &Token<Vec<&str>>) -> Option<Box<dyn Sequence<Vec<St
             &token.content()[1..token.content().len() - 1],
         )))
     } else if token.data.contains(&"quote") {
-        Some(Box::new(MultipleSeq::new(
+        Some(Box::new(MultipleSeq::of(
             token.content()[2..token.content().len() - 2]
                 .chars()
-                .map(|c| Box::new(RawSeq::new(&c.to_string())) as Box<dyn Sequence<Vec<String>>>)
+                .map(|c| Box::new(RawSeq::new(&c.to_string())) as Box<SyncSequence<Vec<String>>>)
                 .collect(),
         )))
     } else if token.data.contains(&"range") {
@@ -492,6 +1420,9 @@ pub fn eval_sequence(token: &Token<Vec<&str>>) -> Option<Box<dyn Sequence<Vec<St
             token.content().chars().nth(0)? as u32,
             token.content().chars().nth(3)? as u32,
         )))
+    } else if token.data.contains(&"def_ref") {
+        let def = defs.get(&token.content()[1..])?;
+        Some(Box::new(Arc::clone(def)))
     } else if token.data.contains(&"word") {
         Some(Box::new(has_tag_owned(token.content().to_string())))
     } else {
@@ -499,71 +1430,634 @@ pub fn eval_sequence(token: &Token<Vec<&str>>) -> Option<Box<dyn Sequence<Vec<St
     }
 }
 
-pub enum DefinedSeq {
-    Raw(String),
-    Range(u32, u32),
-    Choose(Vec<DefinedSeq>),
-    Optional(Box<DefinedSeq>),
-    Repeat(Box<DefinedSeq>),
-    Multiple(Vec<DefinedSeq>),
-    HasTag(String),
+/// Adds and removes tags on matched tokens without replacing them. If the
+/// match contains any captures (`@rule`), only the captured tokens are
+/// retagged.
+#[derive(Clone)]
+pub struct RetagTransform {
+    pub add: Vec<String>,
+    pub remove: Vec<String>,
 }
 
-impl Display for DefinedSeq {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        match self {
-            DefinedSeq::Raw(s) => write!(f, "raw({s})"),
-            DefinedSeq::Range(s, e) => write!(f, "{s}..{e}"),
-            DefinedSeq::Choose(options) => {
-                write!(f, "choose(")?;
-                options.iter().for_each(|o| match write!(f, "{o},") {
-                    Ok(()) => {}
-                    Err(_) => {}
-                });
-                write!(f, ")")
-            }
-            DefinedSeq::Optional(d) => write!(f, "opt({})", d.to_string()),
-            DefinedSeq::Repeat(d) => write!(f, "repeat({})", d.to_string()),
-            DefinedSeq::Multiple(options) => {
-                write!(f, "mult(")?;
-                options.iter().for_each(|o| match write!(f, "{o},") {
-                    Ok(()) => {}
-                    Err(_) => {}
-                });
-                write!(f, ")")
+impl RetagTransform {
+    fn retag(&self, tags: &mut Vec<String>) {
+        tags.retain(|t| !self.remove.contains(t));
+        for tag in &self.add {
+            if !tags.contains(tag) {
+                tags.push(tag.clone());
             }
-            DefinedSeq::HasTag(s) => write!(f, "has({s})"),
         }
     }
+
+    fn would_change(&self, tags: &[String]) -> bool {
+        self.add.iter().any(|t| !tags.contains(t)) || self.remove.iter().any(|t| tags.contains(t))
+    }
 }
 
-impl DefinedSeq {
-    pub fn resolve(&self, prog: &SeqProg) -> Box<dyn Sequence<Vec<String>>> {
-        match self {
-            DefinedSeq::Raw(s) => Box::new(RawSeq::new(&s)),
-            DefinedSeq::Range(s, e) => Box::new(raw_range(*s, *e)),
-            DefinedSeq::Choose(options) => Box::new(ChooseSeq::new(
-                options.iter().map(|d| d.resolve(prog)).collect(),
-            )),
-            DefinedSeq::Optional(d) => Box::new(OptionalSeq::new(d.resolve(prog))),
-            DefinedSeq::Repeat(d) => Box::new(RepeatedSeq::new(d.resolve(prog))),
-            DefinedSeq::Multiple(options) => Box::new(MultipleSeq::new(
-                options.iter().map(|d| d.resolve(prog)).collect(),
-            )),
-            DefinedSeq::HasTag(s) => Box::new(has_tag_owned(s.to_owned())),
+impl Transform<Vec<String>> for RetagTransform {
+    fn transform<'a>(&self, tokens: Vec<Token<'a, Vec<String>>>) -> Vec<Token<'a, Vec<String>>> {
+        let len = tokens.len();
+        self.transform_match(tokens, &Match::whole(len))
+    }
+
+    fn transform_match<'a>(
+        &self,
+        mut tokens: Vec<Token<'a, Vec<String>>>,
+        m: &Match,
+    ) -> Vec<Token<'a, Vec<String>>> {
+        for i in m.marked() {
+            if let Some(tok) = tokens.get_mut(i) {
+                self.retag(&mut tok.data);
+            }
+        }
+        tokens
+    }
+}
+
+/// Which matched tokens an `InheritTransform` copies tags from.
+pub enum InheritFrom {
+    Part(Selector),
+    /// Every matched token with this tag.
+    Tagged(String),
+}
+
+/// Runs `inner`, then copies the tags of the chosen matched tokens onto
+/// every token it produced.
+pub struct InheritTransform {
+    pub inner: Box<SyncTransform<Vec<String>>>,
+    pub from: Vec<InheritFrom>,
+}
+
+impl Transform<Vec<String>> for InheritTransform {
+    fn transform<'a>(&self, tokens: Vec<Token<'a, Vec<String>>>) -> Vec<Token<'a, Vec<String>>> {
+        let len = tokens.len();
+        self.transform_match(tokens, &Match::whole(len))
+    }
+
+    fn transform_match<'a>(
+        &self,
+        tokens: Vec<Token<'a, Vec<String>>>,
+        m: &Match,
+    ) -> Vec<Token<'a, Vec<String>>> {
+        let mut inherited: Vec<String> = vec![];
+        for from in &self.from {
+            let sources: Vec<&Token<Vec<String>>> = match from {
+                InheritFrom::Part(selector) => m
+                    .select(selector)
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|i| tokens.get(i))
+                    .collect(),
+                InheritFrom::Tagged(tag) => {
+                    tokens.iter().filter(|t| t.data.contains(tag)).collect()
+                }
+            };
+            for tag in sources.into_iter().flat_map(|t| &t.data) {
+                if !inherited.contains(tag) {
+                    inherited.push(tag.clone());
+                }
+            }
+        }
+
+        let mut new_tox = self.inner.transform_match(tokens, m);
+        for token in &mut new_tox {
+            for tag in &inherited {
+                if !token.data.contains(tag) {
+                    token.data.push(tag.clone());
+                }
+            }
+        }
+        new_tox
+    }
+
+    /// There are no tokens to inherit from.
+    fn transform_empty<'a>(
+        &self,
+        root: &'a str,
+        at: usize,
+        m: &Match,
+    ) -> Vec<Token<'a, Vec<String>>> {
+        self.inner.transform_empty(root, at, m)
+    }
+}
+
+/// Replaces each matched branch with those of its children that have
+/// `child_tag` (or all of them, if it's `None`). If `merge_tags` is set, the
+/// branch's tags are added to the children that replace it.
+#[derive(Clone)]
+pub struct UnwrapTransform {
+    pub child_tag: Option<String>,
+    pub merge_tags: bool,
+}
+
+impl UnwrapTransform {
+    fn unwraps(&self, token: &Token<Vec<String>>) -> bool {
+        match &token.t_type {
+            TokenType::Branch(children) => match &self.child_tag {
+                Some(tag) => children.iter().any(|c| c.data.contains(tag)),
+                None => true,
+            },
+            _ => false,
+        }
+    }
+}
+
+impl Transform<Vec<String>> for UnwrapTransform {
+    fn transform<'a>(&self, tokens: Vec<Token<'a, Vec<String>>>) -> Vec<Token<'a, Vec<String>>> {
+        let mut to_ret = vec![];
+        for token in tokens {
+            if !self.unwraps(&token) {
+                to_ret.push(token);
+                continue;
+            }
+            if let TokenType::Branch(children) = token.t_type {
+                for mut child in children {
+                    if let Some(tag) = &self.child_tag {
+                        if !child.data.contains(tag) {
+                            continue;
+                        }
+                    }
+                    if self.merge_tags {
+                        for tag in &token.data {
+                            if !child.data.contains(tag) {
+                                child.data.push(tag.clone());
+                            }
+                        }
+                    }
+                    to_ret.push(child);
+                }
+            }
         }
+        to_ret
+    }
+}
+
+/// The names of all of the captures (`name@rule`) in a rule.
+pub fn capture_names<'a>(token: &'a Token<Vec<&str>>) -> Vec<&'a str> {
+    let mut names = vec![];
+    if token.data.contains(&"capture") {
+        names.extend(token.nth_child(0).map(|t| t.content()));
     }
+    for child in token.children().into_iter().flatten() {
+        names.extend(capture_names(child));
+    }
+    names
 }
 
 pub struct SeqProg {
     pub reps: Vec<RepTree>,
+    pub recoveries: Vec<Recovery>,
+    pub layout: Option<Layout>,
+    /// Where `eval_parallel` may split the text, declared with
+    /// `boundary rule;`.
+    pub boundary: Option<Box<SyncSequence<Vec<String>>>>,
+    pub defs: Definitions,
 }
 
 impl SeqProg {
-    fn execute(&self, tokens: &mut Vec<Token<Vec<String>>>) {
-        for rt in &self.reps {
-            rt.execute(self, tokens);
+    /// The sequence declared with `def name = ...;`, or an `UndefinedName`
+    /// error if the program doesn't define `name`.
+    pub fn definition(
+        &self,
+        name: &str,
+    ) -> Result<Arc<SyncSequence<Vec<String>>>, CompileErrorKind> {
+        self.defs
+            .get(name)
+            .cloned()
+            .ok_or_else(|| CompileErrorKind::UndefinedName(name.to_owned()))
+    }
+
+    /// Runs the program on `text`. If the text doesn't reduce to a single
+    /// token, the result also explains where the program got stuck.
+    pub fn eval<'a>(&self, text: &'a str) -> ParseResult<'a> {
+        self.eval_with_limits(text, &ExecutionLimits::default())
+            .expect("a program without limits can't hit them")
+    }
+
+    /// Like `eval`, but gives up with an error once the program has done
+    /// more work than `limits` allow.
+    pub fn eval_with_limits<'a>(
+        &self,
+        text: &'a str,
+        limits: &ExecutionLimits,
+    ) -> Result<ParseResult<'a>, ExecutionError> {
+        self.run(text, Budget::new(limits))
+    }
+
+    /// Like `eval_with_limits`, but tells `tracer` about everything the
+    /// program does along the way.
+    pub fn eval_traced<'a>(
+        &self,
+        text: &'a str,
+        limits: &ExecutionLimits,
+        tracer: &mut dyn Tracer,
+    ) -> Result<ParseResult<'a>, ExecutionError> {
+        self.run(text, Budget::new(limits).traced(tracer))
+    }
+
+    /// Starts running the program on `text`, one rewrite at a time.
+    pub fn stepper<'a>(&self, text: &'a str, limits: &ExecutionLimits) -> Stepper<'_, 'a, '_> {
+        Stepper::new(self, text, Budget::new(limits))
+    }
+
+    /// Like `eval`, but keeps the tokens in an `Arena` while the program
+    /// runs, so a rewrite relinks the tokens around it instead of shifting
+    /// all of the ones after it. Gives the tokens as the rules leave them:
+    /// `recover` declarations aren't run, and nothing explains where the
+    /// program got stuck.
+    pub fn eval_arena<'a>(&self, text: &'a str) -> Arena<'a, Vec<String>> {
+        let mut tokens = char_tokens(text);
+        if let Some(layout) = &self.layout {
+            layout.apply(&mut tokens);
+        }
+        let mut arena = Arena::new(tokens);
+        let mut budget = Budget::new(&ExecutionLimits::default());
+        let mut machine = Machine {
+            stack: vec![Frame::Reps(0)],
+            ..Machine::default()
+        };
+        while machine
+            .advance(Some(self), &mut arena, &mut budget)
+            .expect("a program without limits can't hit them")
+            .is_some()
+        {}
+        arena
+    }
+
+    /// Runs the program over the text `reader` gives, one window at a time,
+    /// as with `StreamParser`. Stops at the first error reading, or if the
+    /// text isn't valid UTF-8, and fails straight away if the program has no
+    /// `boundary` rule.
+    pub fn eval_reader(
+        &self,
+        mut reader: impl Read,
+        mut each: impl FnMut(usize, ParseResult<'_>),
+    ) -> io::Result<()> {
+        let mut stream = StreamParser::new(self)?;
+        let mut bytes = vec![];
+        let mut buf = [0; 8192];
+        loop {
+            let read = match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            bytes.extend_from_slice(&buf[..read]);
+            // A character may be cut off at the end, to be finished by the
+            // next read.
+            let valid = match std::str::from_utf8(&bytes) {
+                Ok(text) => text.len(),
+                Err(e) if e.error_len().is_none() => e.valid_up_to(),
+                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+            };
+            stream.push(std::str::from_utf8(&bytes[..valid]).unwrap(), &mut each);
+            bytes.drain(..valid);
+        }
+        if !bytes.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the text ends partway through a character",
+            ));
+        }
+        stream.finish(each);
+        Ok(())
+    }
+
+    /// Like `eval_reader`, but for text that comes as a series of chunks.
+    pub fn eval_chunks<S: AsRef<str>>(
+        &self,
+        chunks: impl IntoIterator<Item = S>,
+        mut each: impl FnMut(usize, ParseResult<'_>),
+    ) -> Result<(), NoBoundary> {
+        let mut stream = StreamParser::new(self)?;
+        for chunk in chunks {
+            stream.push(chunk.as_ref(), &mut each);
+        }
+        stream.finish(each);
+        Ok(())
+    }
+
+    /// Parses `text`, which is the text `old` was parsed from with `edit`
+    /// made to it, giving the same result as `eval(text)`. The program only
+    /// runs again on the text between the nearest places around the edit
+    /// where the old run can be cut, as no attempt to match a rule looked
+    /// across them, and the tokens on either side are reused as they are. If the new run looks past either end of
+    /// that text, it's widened to the next places out until it doesn't.
+    ///
+    /// Programs with a `layout` or `recover` declarations, results that
+    /// weren't made by `eval` or `reparse`, and edits that don't turn the
+    /// old text into `text` are parsed from scratch.
+    pub fn reparse<'b>(
+        &self,
+        old: ParseResult<'_>,
+        edit: &TextEdit,
+        text: &'b str,
+    ) -> ParseResult<'b> {
+        let (Some(old_text), Some(seams)) = (old.tokens.first().map(|t| t.root), old.seams) else {
+            return self.eval(text);
+        };
+        if self.layout.is_some() || !self.recoveries.is_empty() || !edit.turns(old_text, text) {
+            return self.eval(text);
+        }
+        let moved = |at: usize| at - edit.range.end + edit.range.start + edit.replacement.len();
+        let mut start = seams.cut_before(edit.range.start, old_text);
+        let mut end = seams.cut_after(edit.range.end, old_text);
+        let (tokens, window) = loop {
+            let mut stepper = Stepper::from_tokens(
+                self,
+                char_tokens_in(text, start..moved(end)),
+                vec![],
+                Budget::new(&ExecutionLimits::default()).recording(),
+            );
+            let tokens = stepper
+                .rewrite_rest()
+                .expect("a program without limits can't hit them");
+            let window = stepper.budget.seams(&tokens).expect("the run is recorded");
+            let (first, last) = match (window.spans.first(), window.spans.last()) {
+                (Some(first), Some(last)) => (first.start, last.end),
+                _ => (start, moved(end)),
+            };
+            let left = first < start && start > 0;
+            let right = last > moved(end) && end < old_text.len();
+            if !left && !right {
+                break (tokens, window);
+            }
+            if left {
+                start = seams.cut_before(first, old_text);
+            }
+            if right {
+                end = match last {
+                    usize::MAX => old_text.len(),
+                    last => seams.cut_after(last - moved(end) + end, old_text),
+                };
+            }
+        };
+
+        // A token with no text where the run is cut would be on both sides
+        // of it, so the ends of the text are only taken from the new run.
+        let mut old_tokens = old.tokens.into_iter().peekable();
+        let mut spliced = vec![];
+        while let Some(token) = old_tokens.next_if(|t| start > 0 && t.content_range().end <= start)
+        {
+            spliced.push(rebase(token, 0, 0, text));
+        }
+        spliced.extend(tokens);
+        spliced.extend(
+            old_tokens
+                .filter(|t| end < old_text.len() && t.content_range().start >= end)
+                .map(|t| rebase(t, end, moved(end), text)),
+        );
+        let mut diagnostics = vec![];
+        if spliced.len() > 1 {
+            diagnostics.extend(self.furthest_failure(&spliced));
+        }
+        conversion_errors(&spliced, &mut diagnostics);
+        ParseResult {
+            tokens: spliced,
+            diagnostics,
+            seams: Some(seams.spliced(start, end, moved(end), window)),
+        }
+    }
+
+    fn run<'a>(&self, text: &'a str, budget: Budget) -> Result<ParseResult<'a>, ExecutionError> {
+        Stepper::new(self, text, budget.recording()).finish()
+    }
+
+    /// Finds the rule attempt that got furthest into `tokens` before failing.
+    /// Attempts that failed on their very first token aren't considered, and
+    /// among attempts that stopped at the same place, the one that started
+    /// earliest (i.e. the longest partial match) wins.
+    pub fn furthest_failure(&self, tokens: &[Token<Vec<String>>]) -> Option<Diagnostic> {
+        let refs = tokens.iter().collect::<Vec<_>>();
+        let mut best: Option<(usize, Failure)> = None;
+        for seq in self.reps.iter().flat_map(|rt| rt.sequences()) {
+            for start in 0..refs.len() {
+                let failure = match seq.diagnose(&refs[start..]) {
+                    Err(f) if f.position > 0 => f,
+                    _ => continue,
+                };
+                let end = start + failure.position;
+                best = match best {
+                    Some((best_start, best_fail)) => {
+                        let best_end = best_start + best_fail.position;
+                        if end > best_end || (end == best_end && start < best_start) {
+                            Some((start, failure))
+                        } else if end == best_end && start == best_start {
+                            Some((start, best_fail.furthest(failure)))
+                        } else {
+                            Some((best_start, best_fail))
+                        }
+                    }
+                    None => Some((start, failure)),
+                };
+            }
+        }
+        let (start, failure) = best?;
+        Some(Diagnostic::from_failure(tokens, start, &failure))
+    }
+}
+
+/// A pass over the raw text that runs before any rule, declared with
+/// `layout kind;`.
+pub enum Layout {
+    /// Indentation-sensitive layout. Every non-blank line ends with a virtual
+    /// `newline` token, and a line indented further than the one before it
+    /// starts with a virtual `indent` token. A line indented less starts with
+    /// a virtual `dedent` token for each level it closes, and the levels
+    /// still open at the end of the text are closed there. Indentation is
+    /// counted in characters, so a tab is as wide as a space.
+    Indent,
+}
+
+impl Layout {
+    fn apply(&self, tokens: &mut Vec<Token<Vec<String>>>) -> Vec<Diagnostic> {
+        let root = match tokens.first() {
+            Some(t) => t.root,
+            None => return vec![],
+        };
+        let mut events = vec![];
+        let mut diagnostics = vec![];
+        let mut levels = vec![0];
+        let mut offset = 0;
+        for line in root.split_inclusive('\n') {
+            let body = line.trim_end_matches(['\n', '\r']);
+            let width = body.len() - body.trim_start_matches([' ', '\t']).len();
+            if width < body.len() {
+                let start = offset + width;
+                if width > levels[levels.len() - 1] {
+                    levels.push(width);
+                    events.push((start, "indent"));
+                }
+                while width < levels[levels.len() - 1] {
+                    levels.pop();
+                    events.push((start, "dedent"));
+                }
+                if width != levels[levels.len() - 1] {
+                    diagnostics.push(Diagnostic::new(
+                        "inconsistent dedent".to_string(),
+                        root,
+                        start,
+                    ));
+                }
+                events.push((offset + body.len(), "newline"));
+            }
+            offset += line.len();
+        }
+        events.extend(levels[1..].iter().map(|_| (root.len(), "dedent")));
+
+        let mut events = events.into_iter().peekable();
+        let mut new_tox = Vec::with_capacity(tokens.len() + events.len());
+        for token in tokens.drain(..) {
+            while let Some((at, tag)) = events.next_if(|e| e.0 <= token.content_range().start) {
+                new_tox.push(Token {
+                    root,
+                    t_type: TokenType::Virtual(at, None),
+                    data: vec![tag.to_string()],
+                    value: None,
+                });
+            }
+            new_tox.push(token);
+        }
+        new_tox.extend(events.map(|(at, tag)| Token {
+            root,
+            t_type: TokenType::Virtual(at, None),
+            data: vec![tag.to_string()],
+            value: None,
+        }));
+        *tokens = new_tox;
+        diagnostics
+    }
+}
+
+/// Panic-mode error recovery, declared with `recover tag until rule;`. Once
+/// the program has run, each run of top-level tokens without `tag` is
+/// wrapped into a branch tagged `error`, whose value is the `Diagnostic`
+/// explaining why the run didn't parse. A run ends after the first place
+/// `until` matches, or right before the next token with `tag`.
+pub struct Recovery {
+    pub tag: String,
+    pub until: Box<SyncSequence<Vec<String>>>,
+}
+
+impl Recovery {
+    fn recover(&self, prog: &SeqProg, tokens: &mut Vec<Token<Vec<String>>>) -> Vec<Diagnostic> {
+        let is_done = |t: &Token<Vec<String>>| {
+            t.data.contains(&self.tag) || t.data.iter().any(|d| d == "error")
+        };
+
+        let mut runs = vec![];
+        let refs = tokens.iter().collect::<Vec<_>>();
+        let mut i = 0;
+        while i < refs.len() {
+            if is_done(refs[i]) {
+                i += 1;
+                continue;
+            }
+            let mut end = i;
+            while end < refs.len() && !is_done(refs[end]) {
+                if let Some(len) = self.until.match_tokens(&refs[end..]) {
+                    end += len.max(1);
+                    break;
+                }
+                end += 1;
+            }
+            runs.push(i..end);
+            i = end;
+        }
+
+        let mut diagnostics = vec![];
+        for run in runs.into_iter().rev() {
+            let diag = prog
+                .furthest_failure(&tokens[run.clone()])
+                .unwrap_or_else(|| {
+                    let first = &tokens[run.start];
+                    Diagnostic::new(
+                        "unexpected input".to_string(),
+                        first.root,
+                        first.content_range().start,
+                    )
+                });
+            let children = tokens.drain(run.clone()).collect::<Vec<_>>();
+            tokens.insert(
+                run.start,
+                Token {
+                    root: children[0].root,
+                    t_type: TokenType::Branch(children),
+                    data: vec!["error".to_string()],
+                    value: Some(Box::new(diag.clone())),
+                },
+            );
+            diagnostics.push(diag);
+        }
+        diagnostics.reverse();
+        diagnostics
+    }
+}
+
+/// A human-readable problem found while parsing, located in the input text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub message: String,
+    /// Byte offset into the parsed text.
+    pub offset: usize,
+    /// 1-based line number.
+    pub line: usize,
+    /// 1-based column, counted in characters.
+    pub column: usize,
+}
+
+impl Diagnostic {
+    pub fn new(message: String, root: &str, offset: usize) -> Diagnostic {
+        let before = &root[..offset];
+        let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+        Diagnostic {
+            message,
+            offset,
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+        }
+    }
+
+    /// Describes a `Failure` of an attempt starting at `tokens[start]`.
+    pub fn from_failure(
+        tokens: &[Token<Vec<String>>],
+        start: usize,
+        failure: &Failure,
+    ) -> Diagnostic {
+        let index = start + failure.position;
+        let (root, offset) = match tokens.get(index) {
+            Some(t) => (t.root, t.content_range().start),
+            None => tokens
+                .last()
+                .map(|t| (t.root, t.content_range().end))
+                .unwrap_or(("", 0)),
+        };
+
+        let mut message = if failure.expected.is_empty() {
+            "unexpected input".to_string()
+        } else {
+            format!("expected {}", list_expected(&failure.expected))
+        };
+        if !failure.after.is_empty() {
+            message += &format!(" after {}", list_expected(&failure.after));
         }
+
+        Diagnostic::new(message, root, offset)
+    }
+}
+
+fn list_expected(expected: &[Expected]) -> String {
+    let names = expected.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+    match names.split_last() {
+        Some((last, rest)) if !rest.is_empty() => format!("{} or {last}", rest.join(", ")),
+        _ => names.concat(),
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at {}:{}", self.message, self.line, self.column)
     }
 }
 
@@ -589,93 +2083,2533 @@ impl Debug for SeqProg {
 
 pub enum RepTree {
     Leaf(
-        Box<dyn Sequence<Vec<String>>>,
-        Box<dyn Transform<Vec<String>>>,
+        Box<SyncSequence<Vec<String>>>,
+        Box<SyncTransform<Vec<String>>>,
+        RuleSource,
     ),
     Branch(Vec<RepTree>),
     Once(Box<RepTree>),
+    /// Rules that reduce their rightmost match first, declared with `<`.
+    Reverse(Box<RepTree>),
+    Precedence(Precedence, RuleSource),
+    /// A `%{ }` pass whose rules use modes.
+    Lexer(Lexer),
 }
 
 impl RepTree {
-    pub fn execute(&self, prog: &SeqProg, tokens: &mut Vec<Token<Vec<String>>>) -> bool {
-        match self {
-            RepTree::Branch(children) => {
-                let mut changed_at_least_once = false;
-                let mut i = 0;
-                'outer: while i < children.len() {
-                    let changed = children[i].execute(prog, tokens);
-                    if changed {
-                        i = 0;
-                        changed_at_least_once = true;
-                        continue 'outer;
-                    }
-                    i += 1;
-                }
-                changed_at_least_once
-            }
-            RepTree::Leaf(seq, trans) => replace_first_match(seq.as_ref(), trans.as_ref(), tokens),
-            RepTree::Once(rep) => execute_once(rep, prog, tokens),
-        }
+    /// Runs this tree on `tokens`, returning whether it changed anything.
+    pub fn execute<'a>(
+        &self,
+        tokens: &mut impl TokenStore<'a, Vec<String>>,
+        budget: &mut Budget<'_>,
+    ) -> Result<bool, ExecutionError> {
+        let mut tokens = IndexedTokens::new(tokens, PositionIndex::default());
+        let mut machine = Machine::default();
+        machine.start(self, false, false, &mut tokens, budget)?;
+        while machine.run(None, &mut tokens, budget)?.is_some() {}
+        Ok(machine.changed)
     }
-}
 
-fn execute_once(rep: &RepTree, prog: &SeqProg, tokens: &mut Vec<Token<Vec<String>>>) -> bool {
-    let rep = rep;
-    match rep {
-        RepTree::Leaf(seq, trans) => replace_all_matches_once(seq.as_ref(), trans.as_ref(), tokens),
-        RepTree::Branch(children) => {
-            for t in children {
-                execute_once(t, prog, tokens);
-            }
-            true
+    /// Every sequence in this tree, in the order they're declared.
+    pub fn sequences(&self) -> Vec<&SyncSequence<Vec<String>>> {
+        match self {
+            RepTree::Leaf(seq, _, _) => vec![seq.as_ref()],
+            RepTree::Branch(children) => children.iter().flat_map(|rt| rt.sequences()).collect(),
+            RepTree::Once(rep) | RepTree::Reverse(rep) => rep.sequences(),
+            RepTree::Precedence(prec, _) => prec.rules.iter().map(|r| r.as_ref()).collect(),
+            RepTree::Lexer(lexer) => lexer.rules.iter().map(|r| r.seq.as_ref()).collect(),
         }
-        RepTree::Once(rep) => rep.execute(prog, tokens),
     }
-}
 
-impl Display for RepTree {
-    fn fmt(&self, fmt: &mut Formatter) -> Result<(), std::fmt::Error> {
+    /// Where the first rule in this tree came from, if it has any rules.
+    pub fn source(&self) -> Option<&RuleSource> {
         match self {
-            RepTree::Branch(children) => write!(
-                fmt,
-                "{}",
-                children
-                    .iter()
-                    .map(|rt| format!("{rt}"))
-                    .reduce(|acc, i| acc + &i)
-                    .unwrap_or(String::new())
-            ),
-            RepTree::Leaf(_, _) => write!(fmt, "sequence"),
-            RepTree::Once(r) => write!(fmt, "%{r}"),
+            RepTree::Leaf(_, _, source) => Some(source),
+            RepTree::Branch(children) => children.iter().find_map(|rt| rt.source()),
+            RepTree::Once(rep) | RepTree::Reverse(rep) => rep.source(),
+            RepTree::Precedence(_, source) => Some(source),
+            RepTree::Lexer(lexer) => lexer.rules.first().map(|r| &r.source),
         }
     }
 }
 
-impl Debug for RepTree {
-    fn fmt(&self, fmt: &mut Formatter) -> Result<(), std::fmt::Error> {
-        write!(fmt, "{self}")
-    }
+/// The mode a `Lexer` starts in, and the one its rules are active in unless
+/// they're in an `in` group.
+pub const MAIN_MODE: &str = "main";
+
+/// A `%{ }` pass that keeps track of which mode the text is in, for text that
+/// has different tokens in some regions than in others, like strings with
+/// interpolation. Instead of running each rule over the tokens in turn, it
+/// runs through the tokens once, rewriting each with the first rule that is
+/// active in the current mode and matches there. Rules can enter a mode with
+/// `push(mode)` among their tags and leave it with `pop(mode)`, and are put
+/// in modes with `in mode, ... { }`.
+pub struct Lexer {
+    pub rules: Vec<LexRule>,
 }
 
-pub fn char_to_token(c: char) -> Vec<String> {
-    let mut to_ret = vec![c.to_string(), "u".to_owned() + &(c as u32).to_string()];
+pub struct LexRule {
+    pub seq: Box<SyncSequence<Vec<String>>>,
+    pub trans: Box<SyncTransform<Vec<String>>>,
+    pub source: RuleSource,
+    /// The modes the rule is active in.
+    pub modes: Vec<String>,
+    pub switch: Option<ModeSwitch>,
+}
 
-    if c.is_whitespace() || c.is_control() {
-        to_ret.push("ws".to_string());
-    }
+/// What a rule in a `Lexer` does to the stack of modes when it matches.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ModeSwitch {
+    /// Enters the mode.
+    Push(String),
+    /// Leaves the innermost time the mode was entered, along with any modes
+    /// entered since. Does nothing if the mode hasn't been entered.
+    Pop(String),
+}
+
+impl Lexer {
+    /// Finds the first rule that is active in `mode` and matches at some
+    /// start from `from` on, returning the rule along with where it matched
+    /// and the match. `masks` are the masks of the rules'
+    /// first sets, which let it skip the starts none of them can match at.
+    fn next_match<'a>(
+        &self,
+        mode: &str,
+        masks: &[u64],
+        tokens: &mut IndexedTokens<'_, impl TokenStore<'a, Vec<String>>>,
+        from: usize,
+        budget: &mut Budget<'_>,
+    ) -> Result<Option<(&LexRule, usize, Match)>, ExecutionError> {
+        let active = self
+            .rules
+            .iter()
+            .zip(masks)
+            .filter(|(rule, _)| rule.modes.iter().any(|m| m == mode))
+            .collect::<Vec<_>>();
+        let any = active.iter().fold(0, |bits, (_, mask)| bits | **mask);
+        let mut next = tokens.index.next(from, any);
+        while let Some(start) = next {
+            for (rule, &mask) in &active {
+                if !tokens.admits(start, mask) {
+                    continue;
+                }
+                budget.step(&rule.source, start)?;
+                if let (Some(m), _) = match_at(rule.seq.as_ref(), tokens, start) {
+                    budget.matched(&rule.source, tokens, start, m.len);
+                    return Ok(Some((rule, start, m)));
+                }
+            }
+            next = tokens.index.next(start + 1, any);
+        }
+        Ok(None)
+    }
+
+    /// The masks of the rules' first sets, for `next_match`.
+    fn masks(&self) -> Vec<u64> {
+        self.rules
+            .iter()
+            .map(|rule| rule.seq.first_set().mask())
+            .collect()
+    }
+}
+
+impl ModeSwitch {
+    fn apply<'p>(&'p self, modes: &mut Vec<&'p str>) {
+        match self {
+            ModeSwitch::Push(mode) => modes.push(mode),
+            ModeSwitch::Pop(mode) => {
+                if let Some(i) = modes.iter().rposition(|m| m == mode) {
+                    modes.truncate(i.max(1));
+                }
+            }
+        }
+    }
+}
+
+/// Operators with precedence levels, declared with `precedence tag { ... }`.
+/// Each run of operators and operands (tokens with the tag) is turned into a
+/// tree all at once by precedence climbing, which gives the same tree as a
+/// group with a rule for each level, without rescanning the tokens after
+/// every operator.
+pub struct Precedence {
+    /// The tag of the tokens that the operators apply to.
+    pub operand: String,
+    /// The levels, from the one that binds tightest to the loosest.
+    pub levels: Vec<PrecedenceLevel>,
+    /// The rule each level stands for, for explaining where a run got stuck.
+    rules: Vec<Box<SyncSequence<Vec<String>>>>,
+}
+
+pub struct PrecedenceLevel {
+    pub fixity: Fixity,
+    pub operators: Vec<Operator>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fixity {
+    Left,
+    Right,
+    Prefix,
+}
+
+/// An operator in a precedence level: a single token with some text, or a
+/// token with some tag.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Operator {
+    Raw(String),
+    Tag(String),
+}
+
+impl Operator {
+    fn matches(&self, token: &Token<Vec<String>>) -> bool {
+        match self {
+            Operator::Raw(text) => token.content() == text,
+            Operator::Tag(tag) => token.data.contains(tag),
+        }
+    }
+
+    fn name(&self) -> &str {
+        match self {
+            Operator::Raw(name) | Operator::Tag(name) => name,
+        }
+    }
+
+    fn sequence(&self) -> Box<SyncSequence<Vec<String>>> {
+        match self {
+            Operator::Raw(text) => Box::new(RawSeq::new(text)),
+            Operator::Tag(tag) => Box::new(has_tag_owned(tag.clone())),
+        }
+    }
+}
+
+impl Precedence {
+    pub fn new(operand: String, levels: Vec<PrecedenceLevel>) -> Precedence {
+        let rules = levels
+            .iter()
+            .map(|level| {
+                let operand = || Box::new(has_tag_owned(operand.clone())) as Box<SyncSequence<_>>;
+                let operators = Box::new(ChooseSeq::of(
+                    level.operators.iter().map(Operator::sequence).collect(),
+                )) as Box<SyncSequence<_>>;
+                Box::new(MultipleSeq::of(match level.fixity {
+                    Fixity::Prefix => vec![operators, operand()],
+                    _ => vec![operand(), operators, operand()],
+                })) as Box<SyncSequence<_>>
+            })
+            .collect();
+        Precedence {
+            operand,
+            levels,
+            rules,
+        }
+    }
+
+    /// The level of the first operator `token` is, among those that are
+    /// prefix operators or not, as asked for.
+    fn operator(&self, token: &Token<Vec<String>>, prefix: bool) -> Option<(usize, &Operator)> {
+        self.levels
+            .iter()
+            .enumerate()
+            .filter(|(_, level)| (level.fixity == Fixity::Prefix) == prefix)
+            .find_map(|(i, level)| Some((i, level.operators.iter().find(|op| op.matches(token))?)))
+    }
+
+    fn is_operator(&self, token: &Token<Vec<String>>) -> bool {
+        self.operator(token, true).is_some() || self.operator(token, false).is_some()
+    }
+
+    /// Where an operand (with any prefix operators before it) starting at
+    /// `start` ends, if there is one.
+    fn operand_end(&self, tokens: &[&Token<Vec<String>>], start: usize) -> Option<usize> {
+        let mut i = start;
+        while tokens
+            .get(i)
+            .is_some_and(|t| self.operator(t, true).is_some())
+        {
+            i += 1;
+        }
+        tokens
+            .get(i)
+            .filter(|t| t.data.contains(&self.operand))
+            .map(|_| i + 1)
+    }
+
+    /// The runs of operators and operands in `tokens` that are ready to be
+    /// turned into trees. A run isn't ready if an operator sits right before
+    /// or after it, since what's on the other side of that operator may still
+    /// become an operand.
+    fn runs(&self, tokens: &[&Token<Vec<String>>]) -> Vec<Range<usize>> {
+        let mut runs = vec![];
+        let mut start = 0;
+        while start < tokens.len() {
+            let Some(mut end) = self.operand_end(tokens, start) else {
+                start += 1;
+                continue;
+            };
+            while let Some(next) = tokens
+                .get(end)
+                .filter(|t| self.operator(t, false).is_some())
+                .and_then(|_| self.operand_end(tokens, end + 1))
+            {
+                end = next;
+            }
+            let after_operator = start > 0 && self.is_operator(tokens[start - 1]);
+            let before_operator = tokens.get(end).is_some_and(|t| self.is_operator(t));
+            if !after_operator && !before_operator && end - start > 1 {
+                runs.push(start..end);
+            }
+            start = end;
+        }
+        runs
+    }
+
+    /// Turns a run of operators and operands into a single tree.
+    fn climb<'a>(&self, run: Vec<Token<'a, Vec<String>>>) -> Token<'a, Vec<String>> {
+        let mut run = run.into_iter().peekable();
+        let tree = self.climb_from(&mut run, 0);
+        debug_assert!(run.next().is_none(), "the whole run should be used up");
+        tree
+    }
+
+    /// Reads an operand, along with any operators after it that bind at
+    /// least as tightly as `min`, where the loosest level is 1.
+    fn climb_from<'a>(
+        &self,
+        run: &mut Peekable<std::vec::IntoIter<Token<'a, Vec<String>>>>,
+        min: usize,
+    ) -> Token<'a, Vec<String>> {
+        let strength = |level: usize| self.levels.len() - level;
+        let first = run.next().expect("runs should start with an operand");
+        let mut lhs = match self.operator(&first, true) {
+            Some((level, op)) => {
+                let op = op.clone();
+                let operand = self.climb_from(run, strength(level));
+                self.tree(vec![first, operand], &op, level, "prefix")
+            }
+            None => first,
+        };
+        while let Some((level, op)) = run.peek().and_then(|t| self.operator(t, false)) {
+            if strength(level) < min {
+                break;
+            }
+            let op = op.clone();
+            let next_min = match self.levels[level].fixity {
+                Fixity::Right => strength(level),
+                _ => strength(level) + 1,
+            };
+            let op_token = run.next().unwrap();
+            let rhs = self.climb_from(run, next_min);
+            lhs = self.tree(vec![lhs, op_token, rhs], &op, level, "binary");
+        }
+        lhs
+    }
+
+    fn tree<'a>(
+        &self,
+        children: Vec<Token<'a, Vec<String>>>,
+        op: &Operator,
+        level: usize,
+        kind: &str,
+    ) -> Token<'a, Vec<String>> {
+        Token {
+            root: children[0].root,
+            data: vec![
+                self.operand.clone(),
+                "oper".to_owned(),
+                kind.to_owned(),
+                op.name().to_owned(),
+                format!("level{}", level + 1),
+            ],
+            t_type: TokenType::Branch(children),
+            value: None,
+        }
+    }
+}
+
+impl SeqProg {
+    /// Like `eval`, but runs the `%` passes at the start of the program on
+    /// up to `threads` chunks of the text at once. The text is only split
+    /// right after a match of the program's `boundary` rule, so the passes
+    /// should never need to match across one, e.g. `boundary u10;` for
+    /// passes that don't look past the end of a line. The chunks are put
+    /// back together before the rest of the program runs. Without a
+    /// `boundary`, this is the same as `eval`.
+    pub fn eval_parallel<'a>(&self, text: &'a str, threads: usize) -> ParseResult<'a> {
+        let mut stepper = Stepper::new(self, text, Budget::new(&ExecutionLimits::default()));
+        let passes = self
+            .reps
+            .iter()
+            .take_while(|rep| matches!(rep, RepTree::Once(_)))
+            .count();
+        if let Some(boundary) = &self.boundary {
+            let chunks = split_at_boundaries(
+                std::mem::take(&mut stepper.tokens),
+                boundary.as_ref(),
+                threads,
+            );
+            stepper.tokens = thread::scope(|scope| {
+                let handles = chunks
+                    .into_iter()
+                    .map(|chunk| scope.spawn(move || self.run_passes(chunk, passes)))
+                    .collect::<Vec<_>>();
+                handles
+                    .into_iter()
+                    .flat_map(|handle| handle.join().unwrap_or_else(|e| panic::resume_unwind(e)))
+                    .collect()
+            });
+            stepper.machine.stack = vec![Frame::Reps(passes)];
+        }
+        stepper
+            .finish()
+            .expect("a program without limits can't hit them")
+    }
+
+    /// Runs the first `passes` of the program's rules on `chunk`.
+    fn run_passes<'a>(
+        &self,
+        mut chunk: Vec<Token<'a, Vec<String>>>,
+        passes: usize,
+    ) -> Vec<Token<'a, Vec<String>>> {
+        let mut budget = Budget::new(&ExecutionLimits::default());
+        for rep in &self.reps[..passes] {
+            rep.execute(&mut chunk, &mut budget)
+                .expect("a program without limits can't hit them");
+        }
+        chunk
+    }
+}
+
+/// Splits `tokens` into at most `count` chunks of about the same length,
+/// each of which ends right after a match of `boundary` or at the end.
+fn split_at_boundaries<'a>(
+    mut tokens: Vec<Token<'a, Vec<String>>>,
+    boundary: &dyn Sequence<Vec<String>>,
+    count: usize,
+) -> Vec<Vec<Token<'a, Vec<String>>>> {
+    let size = tokens.len().div_ceil(count.max(1));
+    let mut cuts = vec![];
+    for end in boundary_ends(&tokens, boundary).0 {
+        if end - cuts.last().unwrap_or(&0) >= size {
+            cuts.push(end);
+        }
+    }
+    let mut chunks = cuts
+        .into_iter()
+        .rev()
+        .map(|cut| tokens.split_off(cut))
+        .collect::<Vec<_>>();
+    chunks.push(tokens);
+    chunks.reverse();
+    chunks
+}
+
+/// The index right after each match of `boundary` in `tokens`, along with
+/// where the matches stop being settled: from there on, more tokens could
+/// still change where `boundary` matches, so a match that runs to the end,
+/// or that looked past it, is left out.
+fn boundary_ends(
+    tokens: &[Token<Vec<String>>],
+    boundary: &dyn Sequence<Vec<String>>,
+) -> (Vec<usize>, usize) {
+    let mut ends = vec![];
+    let refs = tokens.iter().collect::<Vec<_>>();
+    let mut i = 0;
+    while i < refs.len() {
+        let (found, reach) = boundary.reach(&refs[i..]);
+        if i + reach > refs.len() {
+            break;
+        }
+        match found {
+            Some(len) if len > 0 && i + len < refs.len() => {
+                i += len;
+                ends.push(i);
+            }
+            Some(len) if len > 0 => break,
+            _ => i += 1,
+        }
+    }
+    (ends, i)
+}
+
+/// Where a rule is in the text of its program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleSource {
+    /// The rule's text, with each run of whitespace collapsed to a space.
+    pub text: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl RuleSource {
+    pub fn new(token: &Token<Vec<&str>>) -> RuleSource {
+        let location = Diagnostic::new(String::new(), token.root, token.content_range().start);
+        RuleSource {
+            text: token
+                .content()
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" "),
+            line: location.line,
+            column: location.column,
+        }
+    }
+}
+
+impl Display for RuleSource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "`{}` at {}:{}", self.text, self.line, self.column)
+    }
+}
+
+/// Limits on how much work running a program may do. The default has none.
+#[derive(Debug, Clone, Default)]
+pub struct ExecutionLimits {
+    /// The most rewrites the program may make.
+    pub max_rewrites: Option<usize>,
+    /// The most times the program may try to match a rule at some position.
+    pub max_steps: Option<usize>,
+    pub deadline: Option<Instant>,
+    /// Whether to stop when rewrites bring the tokens back to a state they
+    /// were already in, which means they would go around in circles forever.
+    pub detect_cycles: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExecutionErrorKind {
+    RewriteLimit,
+    StepLimit,
+    Deadline,
+    Cycle,
+}
+
+/// Why a program was stopped, and the rule it was running at the time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExecutionError {
+    pub kind: ExecutionErrorKind,
+    pub rule: RuleSource,
+}
+
+impl Display for ExecutionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let reason = match self.kind {
+            ExecutionErrorKind::RewriteLimit => "ran out of rewrites",
+            ExecutionErrorKind::StepLimit => "ran out of steps",
+            ExecutionErrorKind::Deadline => "ran past the deadline",
+            ExecutionErrorKind::Cycle => "went around in circles",
+        };
+        write!(f, "{reason} in rule {}", self.rule)
+    }
+}
+
+/// Something that wants to follow along as a program runs, e.g. to find out
+/// why a grammar builds the wrong tree. Every method does nothing by default.
+///
+/// Token indices are positions in the top-level tokens as they are at the
+/// time of the call.
+pub trait Tracer {
+    /// `rule` is about to be tried at token `start`. Starts that a rule is
+    /// already known not to match at are skipped without being tried.
+    fn attempt(&mut self, _rule: &RuleSource, _start: usize) {}
+
+    /// `rule` matched `len` tokens at token `start`, covering `range` of the
+    /// text.
+    fn matched(&mut self, _rule: &RuleSource, _start: usize, _len: usize, _range: Range<usize>) {}
+
+    /// `rule` replaced the `old_len` tokens at token `start` with `new_len`
+    /// others.
+    fn transformed(&mut self, _rule: &RuleSource, _start: usize, _old_len: usize, _new_len: usize) {
+    }
+
+    /// A `{ }` group starting with `first` is about to run. Groups can be
+    /// nested, and every group that is entered also reaches `fixpoint`,
+    /// unless the program stops with an error.
+    fn enter_group(&mut self, _first: &RuleSource) {}
+
+    /// The group starting with `first` is done, because none of its rules
+    /// match anymore. `rewrites` is how many rewrites it made.
+    fn fixpoint(&mut self, _first: &RuleSource, _rewrites: usize) {}
+}
+
+/// A `Tracer` that writes a line for everything that happens to `out`, with
+/// nested groups indented.
+pub struct LogTracer<W: Write> {
+    pub out: W,
+    depth: usize,
+}
+
+impl<W: Write> LogTracer<W> {
+    pub fn new(out: W) -> LogTracer<W> {
+        LogTracer { out, depth: 0 }
+    }
+
+    fn line(&mut self, line: std::fmt::Arguments) {
+        // A log that can't be written to shouldn't stop the program.
+        let _ = writeln!(self.out, "{:indent$}{line}", "", indent = self.depth * 2);
+    }
+}
+
+impl<W: Write> Tracer for LogTracer<W> {
+    fn attempt(&mut self, rule: &RuleSource, start: usize) {
+        self.line(format_args!("try {rule} on token {start}"));
+    }
+
+    fn matched(&mut self, rule: &RuleSource, start: usize, len: usize, range: Range<usize>) {
+        self.line(format_args!(
+            "match {rule} on tokens {start}..{} (text {}..{})",
+            start + len,
+            range.start,
+            range.end
+        ));
+    }
+
+    fn transformed(&mut self, rule: &RuleSource, start: usize, old_len: usize, new_len: usize) {
+        self.line(format_args!(
+            "rewrite {rule} on tokens {start}..{}, leaving {new_len} in their place",
+            start + old_len
+        ));
+    }
+
+    fn enter_group(&mut self, first: &RuleSource) {
+        self.line(format_args!("enter group {first}"));
+        self.depth += 1;
+    }
+
+    fn fixpoint(&mut self, first: &RuleSource, rewrites: usize) {
+        self.depth = self.depth.saturating_sub(1);
+        self.line(format_args!(
+            "leave group {first} after {rewrites} rewrites"
+        ));
+    }
+}
+
+/// Keeps track of the work a program has done against its `ExecutionLimits`,
+/// and tells its `Tracer`, if it has one, about it.
+pub struct Budget<'t> {
+    limits: ExecutionLimits,
+    rewrites: usize,
+    steps: usize,
+    tracer: Option<&'t mut dyn Tracer>,
+    /// The parts of the text that attempts looked across, if they're being
+    /// kept for `Seams`.
+    looked: Option<Vec<Range<usize>>>,
+}
+
+impl<'t> Budget<'t> {
+    pub fn new(limits: &ExecutionLimits) -> Budget<'t> {
+        Budget {
+            limits: limits.clone(),
+            rewrites: 0,
+            steps: 0,
+            tracer: None,
+            looked: None,
+        }
+    }
+
+    /// Keeps track of how far attempts look, so that the result can be
+    /// reparsed.
+    fn recording(self) -> Budget<'t> {
+        Budget {
+            looked: Some(vec![]),
+            ..self
+        }
+    }
+
+    pub fn traced(self, tracer: &'t mut dyn Tracer) -> Budget<'t> {
+        Budget {
+            tracer: Some(tracer),
+            ..self
+        }
+    }
+
+    fn error(kind: ExecutionErrorKind, rule: &RuleSource) -> ExecutionError {
+        ExecutionError {
+            kind,
+            rule: rule.clone(),
+        }
+    }
+
+    fn check_deadline(&self, rule: &RuleSource) -> Result<(), ExecutionError> {
+        match self.limits.deadline {
+            Some(deadline) if Instant::now() > deadline => {
+                Err(Budget::error(ExecutionErrorKind::Deadline, rule))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Counts an attempt to match `rule` at `start`.
+    fn step(&mut self, rule: &RuleSource, start: usize) -> Result<(), ExecutionError> {
+        if let Some(tracer) = &mut self.tracer {
+            tracer.attempt(rule, start);
+        }
+        self.steps += 1;
+        if self.limits.max_steps.is_some_and(|max| self.steps > max) {
+            return Err(Budget::error(ExecutionErrorKind::StepLimit, rule));
+        }
+        // Looking at the clock is slow next to a single attempt.
+        if self.steps.is_multiple_of(256) {
+            self.check_deadline(rule)?;
+        }
+        Ok(())
+    }
+
+    /// Counts a rewrite made by `rule`.
+    fn rewrite(&mut self, rule: &RuleSource) -> Result<(), ExecutionError> {
+        self.rewrites += 1;
+        if self
+            .limits
+            .max_rewrites
+            .is_some_and(|max| self.rewrites > max)
+        {
+            return Err(Budget::error(ExecutionErrorKind::RewriteLimit, rule));
+        }
+        self.check_deadline(rule)
+    }
+
+    fn matched<'a>(
+        &mut self,
+        rule: &RuleSource,
+        tokens: &mut impl TokenStore<'a, Vec<String>>,
+        start: usize,
+        len: usize,
+    ) {
+        if let Some(tracer) = &mut self.tracer {
+            let range = range_at(tokens, start, len);
+            tracer.matched(rule, start, len, range);
+        }
+    }
+
+    /// Notes that an attempt at `start` looked at `reach` tokens, as
+    /// `match_at` reports it. An attempt that only looked at its first
+    /// token doesn't tie it to anything else, so it isn't kept.
+    fn looked<'a>(
+        &mut self,
+        tokens: &mut impl TokenStore<'a, Vec<String>>,
+        start: usize,
+        reach: usize,
+    ) {
+        let Some(looked) = &mut self.looked else {
+            return;
+        };
+        let rest = tokens.len().saturating_sub(start);
+        if reach <= 1 && reach <= rest {
+            return;
+        }
+        // An attempt that ran off the end depends on where the tokens end.
+        // A token with no text is tied to the tokens on either side of it,
+        // so a span that ends in one takes in the position it sits at.
+        let span = match rest {
+            0 if start == 0 => 0..usize::MAX,
+            0 => tokens.with_window(start - 1, 1, |w| w[0].content_range().end..usize::MAX),
+            _ => tokens.with_window(start, reach.min(rest), |w| {
+                let first = w[0].content_range();
+                let last = w[w.len() - 1].content_range();
+                let lo = match first.is_empty() {
+                    true => first.start.saturating_sub(1),
+                    false => first.start,
+                };
+                let hi = match (reach > rest, last.is_empty()) {
+                    (true, _) => usize::MAX,
+                    (false, true) => last.end + 1,
+                    (false, false) => last.end,
+                };
+                lo..hi
+            }),
+        };
+        looked.push(span);
+    }
+
+    /// Notes that something looked at all of the tokens at once.
+    fn looked_everywhere(&mut self) {
+        if let Some(looked) = &mut self.looked {
+            looked.push(0..usize::MAX);
+        }
+    }
+
+    /// The seams of a run that left `tokens`, if attempts were kept track of.
+    fn seams(&mut self, tokens: &[Token<Vec<String>>]) -> Option<Seams> {
+        let mut spans = self.looked.take()?;
+        spans.extend(tokens.iter().map(|t| match t.content_range() {
+            r if r.is_empty() => r.start.saturating_sub(1)..r.start + 1,
+            r => r,
+        }));
+        Some(Seams::new(spans))
+    }
+
+    fn transformed(&mut self, rule: &RuleSource, start: usize, old_len: usize, new_len: usize) {
+        if let Some(tracer) = &mut self.tracer {
+            tracer.transformed(rule, start, old_len, new_len);
+        }
+    }
+
+    fn enter_group(&mut self, first: &RuleSource) {
+        if let Some(tracer) = &mut self.tracer {
+            tracer.enter_group(first);
+        }
+    }
+
+    fn fixpoint(&mut self, first: &RuleSource, rewrites: usize) {
+        if let Some(tracer) = &mut self.tracer {
+            tracer.fixpoint(first, rewrites);
+        }
+    }
+
+    /// Finds the first start from `from` on where `seq` matches, returning
+    /// it along with the match. Only the starts whose token
+    /// shares a bit with `mask`, the mask of `seq`'s first set, are tried.
+    fn first_match<'a>(
+        &mut self,
+        seq: &dyn Sequence<Vec<String>>,
+        mask: u64,
+        rule: &RuleSource,
+        tokens: &mut IndexedTokens<'_, impl TokenStore<'a, Vec<String>>>,
+        from: usize,
+    ) -> Result<Option<(usize, Match)>, ExecutionError> {
+        let mut next = tokens.index.next(from, mask);
+        while let Some(start) = next {
+            self.step(rule, start)?;
+            let (found, reach) = match_at(seq, tokens, start);
+            self.looked(tokens, start, reach);
+            if let Some(m) = found {
+                self.matched(rule, tokens, start, m.len);
+                return Ok(Some((start, m)));
+            }
+            next = tokens.index.next(start + 1, mask);
+        }
+        Ok(None)
+    }
+
+    /// Finds the last start before `before` where `seq` matches without
+    /// reaching `before`, returning it along with the match.
+    /// `mask` is as in `first_match`.
+    fn last_match<'a>(
+        &mut self,
+        seq: &dyn Sequence<Vec<String>>,
+        mask: u64,
+        rule: &RuleSource,
+        tokens: &mut IndexedTokens<'_, impl TokenStore<'a, Vec<String>>>,
+        before: usize,
+    ) -> Result<Option<(usize, Match)>, ExecutionError> {
+        let mut next = tokens.index.prev(before, mask);
+        while let Some(start) = next {
+            self.step(rule, start)?;
+            let (found, reach) = match_at(seq, tokens, start);
+            self.looked(tokens, start, reach);
+            match found {
+                Some(m) if start + m.len <= before => {
+                    self.matched(rule, tokens, start, m.len);
+                    return Ok(Some((start, m)));
+                }
+                _ => {}
+            }
+            next = tokens.index.prev(start, mask);
+        }
+        Ok(None)
+    }
+}
+
+/// Tries `seq` at `start`, reporting where its elements landed if it
+/// matches, and how many tokens it looked at, as `Sequence::reach` does.
+fn match_at<'a>(
+    seq: &dyn Sequence<Vec<String>>,
+    tokens: &mut impl TokenStore<'a, Vec<String>>,
+    start: usize,
+) -> (Option<Match>, usize) {
+    look_at(tokens, start, |window| {
+        let (found, span) = seq.reach(window);
+        let m = found.map(|len| {
+            seq.match_elements(window)
+                .unwrap_or_else(|| Match::whole(len))
+        });
+        (m, span)
+    })
+}
+
+/// Calls `f` on the tokens from `start` on, where `f` also says how many of
+/// them it looked at, as `Sequence::reach` does. Only gathers about as many
+/// tokens as that, so a match doesn't take time in proportion to all of the
+/// tokens after it.
+fn look_at<'a, R>(
+    tokens: &mut impl TokenStore<'a, Vec<String>>,
+    start: usize,
+    f: impl Fn(&[&Token<'a, Vec<String>>]) -> (R, usize),
+) -> (R, usize) {
+    let rest = tokens.len().saturating_sub(start);
+    let mut len = 8;
+    loop {
+        // A result that depends on the end of the window is only right if
+        // the window goes to the end of the tokens.
+        let (found, span) = tokens.with_window(start, len, &f);
+        if span <= len || len >= rest {
+            return (found, span);
+        }
+        len *= 2;
+    }
+}
+
+/// The part of the text covered by the `len` tokens at `start`.
+fn range_at<'a>(
+    tokens: &mut impl TokenStore<'a, Vec<String>>,
+    start: usize,
+    len: usize,
+) -> Range<usize> {
+    match start {
+        // An empty match at the end sits where the last token ends.
+        s if s > 0 && s >= tokens.len() => tokens.with_window(s - 1, 1, |w| text_range(w, 1, 0)),
+        s => tokens.with_window(s, len.max(1), |w| text_range(w, 0, len)),
+    }
+}
+
+/// The states a run of rewrites has been through, if cycles are detected.
+struct History {
+    seen: Option<HashSet<u64>>,
+}
+
+impl History {
+    fn new(budget: &Budget) -> History {
+        History {
+            seen: budget.limits.detect_cycles.then(HashSet::new),
+        }
+    }
+
+    /// Records the state `tokens` are in (along with `extra`, for any other
+    /// state that decides what happens next), failing if it has been seen.
+    fn record<'a>(
+        &mut self,
+        tokens: &mut impl TokenStore<'a, Vec<String>>,
+        extra: usize,
+        rule: &RuleSource,
+    ) -> Result<(), ExecutionError> {
+        if let Some(seen) = &mut self.seen {
+            let mut hasher = DefaultHasher::new();
+            extra.hash(&mut hasher);
+            tokens.with_all(|refs| hash_tokens(refs, &mut hasher));
+            if !seen.insert(hasher.finish()) {
+                return Err(Budget::error(ExecutionErrorKind::Cycle, rule));
+            }
+        }
+        Ok(())
+    }
+
+    fn clear(&mut self) {
+        if let Some(seen) = &mut self.seen {
+            seen.clear();
+        }
+    }
+}
+
+fn hash_tokens<'a, T: Borrow<Token<'a, Vec<String>>>>(tokens: &[T], hasher: &mut DefaultHasher) {
+    tokens.len().hash(hasher);
+    for token in tokens {
+        let token = token.borrow();
+        token.content_range().hash(hasher);
+        token.data.hash(hasher);
+        token.value.is_some().hash(hasher);
+        match &token.t_type {
+            TokenType::Leaf(_) => 0.hash(hasher),
+            TokenType::Virtual(_, text) => text.hash(hasher),
+            TokenType::Branch(children) => hash_tokens(children, hasher),
+        }
+    }
+}
+
+/// A single rewrite made while running a program.
+#[derive(Debug, Clone)]
+pub struct Step<'p> {
+    /// The rule that made the rewrite.
+    pub rule: &'p RuleSource,
+    /// Where the rewritten tokens started among the top-level tokens.
+    pub start: usize,
+    /// How many tokens the rule matched.
+    pub old_len: usize,
+    /// How many tokens took their place.
+    pub new_len: usize,
+    /// The part of the text that the matched tokens covered.
+    pub range: Range<usize>,
+}
+
+impl Step<'_> {
+    /// The tokens that the rewrite left behind, given the tokens as they were
+    /// right after it.
+    pub fn created<'t, 'a>(
+        &self,
+        tokens: &'t [Token<'a, Vec<String>>],
+    ) -> &'t [Token<'a, Vec<String>>] {
+        &tokens[self.start..self.start + self.new_len]
+    }
+
+    /// Whether the rewrite left behind a token with the tag `tag`.
+    pub fn creates(&self, tokens: &[Token<Vec<String>>], tag: &str) -> bool {
+        self.created(tokens)
+            .iter()
+            .any(|t| t.data.iter().any(|d| d == tag))
+    }
+}
+
+/// Runs a program over text that arrives a piece at a time, like a log file
+/// or a socket, for programs that parse it one record at a time. The text is
+/// cut into windows that each end right after a match of the program's
+/// `boundary` rule, and each window is run on its own as soon as it's
+/// complete, so only the text of the window being read is kept around.
+///
+/// A boundary is only trusted once enough text follows it that more text
+/// can't change it, so a window that ends the text is run by `finish`.
+pub struct StreamParser<'p> {
+    prog: &'p SeqProg,
+    boundary: &'p dyn Sequence<Vec<String>>,
+    buffer: String,
+    /// Where the windows in `buffer` start in the whole text.
+    offset: usize,
+    /// How much of `buffer` has been looked through for boundaries, so each
+    /// push only reads the text it adds.
+    scanned: usize,
+}
+
+/// A program can't be streamed without a `boundary` rule, since the whole
+/// text would have to be kept until the end to run it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NoBoundary;
+
+impl Display for NoBoundary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "the program has no `boundary` rule to split the text at")
+    }
+}
+
+impl From<NoBoundary> for io::Error {
+    fn from(err: NoBoundary) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidInput, err.to_string())
+    }
+}
+
+impl<'p> StreamParser<'p> {
+    pub fn new(prog: &'p SeqProg) -> Result<StreamParser<'p>, NoBoundary> {
+        Ok(StreamParser {
+            prog,
+            boundary: prog.boundary.as_deref().ok_or(NoBoundary)?,
+            buffer: String::new(),
+            offset: 0,
+            scanned: 0,
+        })
+    }
+
+    /// Adds `chunk` to the end of the text and runs every window it
+    /// completes, giving `each` where the window starts in the whole text
+    /// and what the program made of it.
+    pub fn push(&mut self, chunk: &str, mut each: impl FnMut(usize, ParseResult<'_>)) {
+        self.buffer.push_str(chunk);
+        let tokens = char_tokens(&self.buffer[self.scanned..]);
+        let (ends, settled) = boundary_ends(&tokens, self.boundary);
+        let at = |i: usize| {
+            self.scanned
+                + tokens.get(i).map_or(self.buffer.len() - self.scanned, |t| {
+                    t.content_range().start
+                })
+        };
+        let mut start = 0;
+        for end in ends {
+            let end = at(end);
+            each(
+                self.offset + start,
+                self.prog.eval(&self.buffer[start..end]),
+            );
+            start = end;
+        }
+        self.scanned = at(settled) - start;
+        self.buffer.drain(..start);
+        self.offset += start;
+    }
+
+    /// Runs whatever text is left as the last window.
+    pub fn finish(self, mut each: impl FnMut(usize, ParseResult<'_>)) {
+        if !self.buffer.is_empty() {
+            each(self.offset, self.prog.eval(&self.buffer));
+        }
+    }
+}
+
+/// Runs a program one rewrite at a time, as an iterator over the rewrites it
+/// makes. Between rewrites, `tokens` shows what the program has done so far,
+/// so a debugger can stop wherever it likes and carry on afterwards.
+///
+/// Once the iterator runs out (or gives an error), `finish` gives the same
+/// result `SeqProg::eval` would have.
+pub struct Stepper<'p, 'a, 't> {
+    prog: &'p SeqProg,
+    tokens: Vec<Token<'a, Vec<String>>>,
+    budget: Budget<'t>,
+    machine: Machine<'p>,
+    diagnostics: Vec<Diagnostic>,
+    error: Option<ExecutionError>,
+}
+
+impl<'p, 'a, 't> Stepper<'p, 'a, 't> {
+    pub fn new(prog: &'p SeqProg, text: &'a str, budget: Budget<'t>) -> Stepper<'p, 'a, 't> {
+        let mut tokens = char_tokens(text);
+        let diagnostics = match &prog.layout {
+            Some(layout) => layout.apply(&mut tokens),
+            None => vec![],
+        };
+        Stepper::from_tokens(prog, tokens, diagnostics, budget)
+    }
+
+    fn from_tokens(
+        prog: &'p SeqProg,
+        tokens: Vec<Token<'a, Vec<String>>>,
+        diagnostics: Vec<Diagnostic>,
+        budget: Budget<'t>,
+    ) -> Stepper<'p, 'a, 't> {
+        Stepper {
+            prog,
+            tokens,
+            budget,
+            machine: Machine {
+                stack: vec![Frame::Reps(0)],
+                ..Machine::default()
+            },
+            diagnostics,
+            error: None,
+        }
+    }
+
+    /// The tokens as they are after the last rewrite.
+    pub fn tokens(&self) -> &[Token<'a, Vec<String>>] {
+        &self.tokens
+    }
+
+    /// Keeps running until `stop` is true of a rewrite and the tokens right
+    /// after it, e.g. `|step, tokens| step.creates(tokens, "oper")`, and
+    /// gives that rewrite. Gives `None` if the program finishes first.
+    pub fn run_until(
+        &mut self,
+        mut stop: impl FnMut(&Step<'p>, &[Token<'a, Vec<String>>]) -> bool,
+    ) -> Option<Result<Step<'p>, ExecutionError>> {
+        loop {
+            match self.next()? {
+                Ok(step) if !stop(&step, &self.tokens) => continue,
+                result => return Some(result),
+            }
+        }
+    }
+
+    /// Runs the rest of the program, including its `recover` declarations.
+    pub fn finish(mut self) -> Result<ParseResult<'a>, ExecutionError> {
+        let mut tokens = self.rewrite_rest()?;
+        let seams = self.budget.seams(&tokens);
+        let prog = self.prog;
+        let mut diagnostics = self.diagnostics;
+        diagnostics.extend(
+            prog.recoveries
+                .iter()
+                .flat_map(|r| r.recover(prog, &mut tokens)),
+        );
+        if diagnostics.is_empty() && tokens.len() > 1 {
+            diagnostics.extend(prog.furthest_failure(&tokens));
+        }
+        conversion_errors(&tokens, &mut diagnostics);
+        Ok(ParseResult {
+            tokens,
+            diagnostics,
+            seams,
+        })
+    }
+
+    /// Makes the rest of the rewrites, giving the tokens they leave.
+    fn rewrite_rest(&mut self) -> Result<Vec<Token<'a, Vec<String>>>, ExecutionError> {
+        // Nothing looks at the tokens between the rest of the rewrites, so
+        // they can be kept in an `Arena`, where a rewrite doesn't move all
+        // of the tokens after it.
+        let mut arena = Arena::new(std::mem::take(&mut self.tokens));
+        while self.error.is_none() {
+            match self
+                .machine
+                .advance(Some(self.prog), &mut arena, &mut self.budget)
+            {
+                Ok(Some(_)) => {}
+                Ok(None) => break,
+                Err(error) => self.error = Some(error),
+            }
+        }
+        match self.error.take() {
+            Some(error) => Err(error),
+            None => Ok(arena.into_tokens()),
+        }
+    }
+}
+
+impl<'p> Iterator for Stepper<'p, '_, '_> {
+    type Item = Result<Step<'p>, ExecutionError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.error.is_some() {
+            return None;
+        }
+        match self
+            .machine
+            .advance(Some(self.prog), &mut self.tokens, &mut self.budget)
+        {
+            Ok(step) => step.map(Ok),
+            Err(error) => {
+                self.error = Some(error.clone());
+                Some(Err(error))
+            }
+        }
+    }
+}
+
+/// Something the engine is in the middle of running.
+enum Frame<'p> {
+    /// The program's top-level rules, and the index of the next one to run.
+    Reps(usize),
+    /// A `{ }` group.
+    Group(Group<'p>),
+    /// A `%` pass of a single rule over the tokens.
+    Pass {
+        seq: &'p dyn Sequence<Vec<String>>,
+        /// The mask of `seq`'s first set.
+        mask: u64,
+        trans: &'p dyn Transform<Vec<String>>,
+        source: &'p RuleSource,
+        /// Where to look for the next match: the first start to try, or if
+        /// the pass is reversed, the end that matches have to stay before.
+        start: usize,
+        reverse: bool,
+        changed: bool,
+        history: History,
+    },
+    /// A `%` pass of a group, which runs each of its children once, the
+    /// index of the next child to run, and whether it's reversed.
+    Passes(&'p [RepTree], usize, bool),
+    /// A `Lexer`, the modes it has entered, and where to look for the next
+    /// match.
+    Scan {
+        lexer: &'p Lexer,
+        masks: Vec<u64>,
+        modes: Vec<&'p str>,
+        start: usize,
+        changed: bool,
+        history: History,
+    },
+    /// A `%` pass of a `precedence` declaration. `start` is as in `Pass`.
+    Climb {
+        prec: &'p Precedence,
+        source: &'p RuleSource,
+        start: usize,
+        reverse: bool,
+        changed: bool,
+    },
+}
+
+struct Group<'p> {
+    children: &'p [RepTree],
+    first: &'p RuleSource,
+    /// The masks of the children's first sets.
+    masks: Vec<u64>,
+    worklists: Vec<Worklist>,
+    history: History,
+    rewrites: usize,
+    /// The child the group is looking at.
+    i: usize,
+    reverse: bool,
+}
+
+/// Runs rule trees a rewrite at a time, keeping track of where it is in each
+/// tree it's running.
+#[derive(Default)]
+struct Machine<'p> {
+    stack: Vec<Frame<'p>>,
+    /// Whether the tree the machine was started on changed anything.
+    changed: bool,
+    /// The index of the tokens it was last run on, kept between calls to
+    /// `advance`.
+    index: PositionIndex,
+}
+
+impl<'p> Machine<'p> {
+    /// Starts running `rep`, once over the tokens if `once` is set and from
+    /// right to left if `reverse` is, returning the rewrite it made if it
+    /// made one straight away.
+    fn start<'a>(
+        &mut self,
+        rep: &'p RepTree,
+        once: bool,
+        reverse: bool,
+        tokens: &mut IndexedTokens<'_, impl TokenStore<'a, Vec<String>>>,
+        budget: &mut Budget<'_>,
+    ) -> Result<Option<Step<'p>>, ExecutionError> {
+        match (rep, once) {
+            // Where the first match is depends on all of the tokens.
+            (RepTree::Leaf(seq, trans, source), false) => {
+                budget.looked_everywhere();
+                let mask = seq.first_set().mask();
+                let found = match reverse {
+                    true => budget.last_match(seq.as_ref(), mask, source, tokens, tokens.len())?,
+                    false => budget.first_match(seq.as_ref(), mask, source, tokens, 0)?,
+                };
+                let step = match found {
+                    Some((start, m)) => {
+                        Some(rewrite(trans.as_ref(), source, tokens, budget, start, m)?)
+                    }
+                    None => None,
+                };
+                self.returned(step.is_some(), tokens)?;
+                Ok(step)
+            }
+            (RepTree::Leaf(seq, trans, source), true) => {
+                self.stack.push(Frame::Pass {
+                    seq: seq.as_ref(),
+                    mask: seq.first_set().mask(),
+                    trans: trans.as_ref(),
+                    source,
+                    start: if reverse { tokens.len() } else { 0 },
+                    reverse,
+                    changed: false,
+                    history: History::new(budget),
+                });
+                Ok(None)
+            }
+            (RepTree::Branch(children), false) => {
+                let Some(first) = children.iter().find_map(|c| c.source()) else {
+                    self.returned(false, tokens)?;
+                    return Ok(None);
+                };
+                let mut history = History::new(budget);
+                history.record(tokens, 0, first)?;
+                budget.enter_group(first);
+                self.stack.push(Frame::Group(Group {
+                    children,
+                    first,
+                    masks: children
+                        .iter()
+                        .map(|child| match child {
+                            RepTree::Leaf(seq, _, _) => seq.first_set().mask(),
+                            _ => u64::MAX,
+                        })
+                        .collect(),
+                    worklists: children
+                        .iter()
+                        .map(|_| Worklist::new(tokens.len(), reverse))
+                        .collect(),
+                    history,
+                    rewrites: 0,
+                    i: 0,
+                    reverse,
+                }));
+                Ok(None)
+            }
+            (RepTree::Branch(children), true) => {
+                self.stack.push(Frame::Passes(children, 0, reverse));
+                Ok(None)
+            }
+            (RepTree::Once(rep), once) => self.start(rep, !once, reverse, tokens, budget),
+            (RepTree::Reverse(rep), once) => self.start(rep, once, true, tokens, budget),
+            // So do the runs of operands, and a lexer's modes, which it
+            // keeps from one match to the next.
+            (RepTree::Precedence(prec, source), false) => {
+                budget.looked_everywhere();
+                budget.step(source, 0)?;
+                let runs = tokens.with_all(|refs| prec.runs(refs));
+                let run = match reverse {
+                    true => runs.last(),
+                    false => runs.first(),
+                };
+                let step = match run {
+                    Some(run) => Some(climb(prec, source, tokens, budget, run.clone())?),
+                    None => None,
+                };
+                self.returned(step.is_some(), tokens)?;
+                Ok(step)
+            }
+            // A lexer always runs forwards, since modes are entered from the
+            // start of the text.
+            (RepTree::Lexer(lexer), _) => {
+                budget.looked_everywhere();
+                self.stack.push(Frame::Scan {
+                    lexer,
+                    masks: lexer.masks(),
+                    modes: vec![MAIN_MODE],
+                    start: 0,
+                    changed: false,
+                    history: History::new(budget),
+                });
+                Ok(None)
+            }
+            (RepTree::Precedence(prec, source), true) => {
+                budget.looked_everywhere();
+                self.stack.push(Frame::Climb {
+                    prec,
+                    source,
+                    start: if reverse { tokens.len() } else { 0 },
+                    reverse,
+                    changed: false,
+                });
+                Ok(None)
+            }
+        }
+    }
+
+    /// Lets whatever started a tree that just finished know whether it
+    /// changed anything.
+    fn returned<'a>(
+        &mut self,
+        changed: bool,
+        tokens: &mut impl TokenStore<'a, Vec<String>>,
+    ) -> Result<(), ExecutionError> {
+        match self.stack.last_mut() {
+            Some(Frame::Group(group)) if changed => {
+                if let Some(source) = group.children[group.i].source() {
+                    group.history.record(tokens, 0, source)?;
+                }
+                group.rewrites += 1;
+                group.i = 0;
+            }
+            Some(Frame::Group(group)) => group.i += 1,
+            Some(_) => {}
+            None => self.changed = changed,
+        }
+        Ok(())
+    }
+
+    /// Runs until the next rewrite, returning it, or until there's nothing
+    /// left to run. `prog` is needed if the machine is running a program's
+    /// top-level rules.
+    fn advance<'a>(
+        &mut self,
+        prog: Option<&'p SeqProg>,
+        tokens: &mut impl TokenStore<'a, Vec<String>>,
+        budget: &mut Budget<'_>,
+    ) -> Result<Option<Step<'p>>, ExecutionError> {
+        let mut tokens = IndexedTokens::new(tokens, std::mem::take(&mut self.index));
+        let step = self.run(prog, &mut tokens, budget);
+        self.index = tokens.index;
+        step
+    }
+
+    /// Like `advance`, on tokens that are already indexed.
+    fn run<'a>(
+        &mut self,
+        prog: Option<&'p SeqProg>,
+        tokens: &mut IndexedTokens<'_, impl TokenStore<'a, Vec<String>>>,
+        budget: &mut Budget<'_>,
+    ) -> Result<Option<Step<'p>>, ExecutionError> {
+        let step = self.next_step(prog, tokens, budget)?;
+        if let Some(step) = &step {
+            self.rewritten(step);
+        }
+        Ok(step)
+    }
+
+    /// Lets every group being run know about a rewrite, wherever it was
+    /// made, so that its rules only look at the starts it could affect.
+    fn rewritten(&mut self, step: &Step) {
+        for frame in &mut self.stack {
+            if let Frame::Group(group) = frame {
+                for worklist in &mut group.worklists {
+                    worklist.rewrite(step.start, step.old_len, step.new_len);
+                }
+            }
+        }
+    }
+
+    fn next_step<'a>(
+        &mut self,
+        prog: Option<&'p SeqProg>,
+        tokens: &mut IndexedTokens<'_, impl TokenStore<'a, Vec<String>>>,
+        budget: &mut Budget<'_>,
+    ) -> Result<Option<Step<'p>>, ExecutionError> {
+        loop {
+            let next = match self.stack.last_mut() {
+                None => return Ok(None),
+                Some(Frame::Reps(i)) => match prog.and_then(|prog| prog.reps.get(*i)) {
+                    Some(rep) => {
+                        *i += 1;
+                        Some((rep, false, false))
+                    }
+                    None => {
+                        self.stack.pop();
+                        None
+                    }
+                },
+                Some(Frame::Passes(children, i, reverse)) => match children.get(*i) {
+                    Some(child) => {
+                        *i += 1;
+                        Some((child, true, *reverse))
+                    }
+                    None => {
+                        self.stack.pop();
+                        self.returned(true, tokens)?;
+                        None
+                    }
+                },
+                Some(Frame::Pass {
+                    seq,
+                    mask,
+                    trans,
+                    source,
+                    start,
+                    reverse,
+                    changed,
+                    history,
+                }) => {
+                    let found = match reverse {
+                        true => budget.last_match(*seq, *mask, source, tokens, *start)?,
+                        false => budget.first_match(*seq, *mask, source, tokens, *start)?,
+                    };
+                    if let Some((found, m)) = found {
+                        let step = rewrite(*trans, source, tokens, budget, found, m)?;
+                        // Only a rewrite that leaves nothing behind can keep
+                        // the pass where it is. A reversed pass always moves
+                        // on, since its matches have to end before the last.
+                        if step.new_len == 0 && !*reverse {
+                            history.record(tokens, found, source)?;
+                        } else {
+                            history.clear();
+                        }
+                        // After an empty match, the pass moves on a token, so
+                        // that it doesn't match in the same place again.
+                        *start = if *reverse {
+                            found
+                        } else {
+                            found + step.new_len + usize::from(step.old_len == 0)
+                        };
+                        *changed = true;
+                        return Ok(Some(step));
+                    }
+                    let changed = *changed;
+                    self.stack.pop();
+                    self.returned(changed, tokens)?;
+                    None
+                }
+                Some(Frame::Scan {
+                    lexer,
+                    masks,
+                    modes,
+                    start,
+                    changed,
+                    history,
+                }) => {
+                    let mode = modes.last().copied().unwrap_or(MAIN_MODE);
+                    let found = lexer.next_match(mode, masks, tokens, *start, budget)?;
+                    if let Some((rule, found, m)) = found {
+                        let trans = rule.trans.as_ref();
+                        let step = rewrite(trans, &rule.source, tokens, budget, found, m)?;
+                        if step.new_len == 0 {
+                            history.record(tokens, found, &rule.source)?;
+                        } else {
+                            history.clear();
+                        }
+                        if let Some(switch) = &rule.switch {
+                            switch.apply(modes);
+                        }
+                        *start = found + step.new_len + usize::from(step.old_len == 0);
+                        *changed = true;
+                        return Ok(Some(step));
+                    }
+                    let changed = *changed;
+                    self.stack.pop();
+                    self.returned(changed, tokens)?;
+                    None
+                }
+                Some(Frame::Climb {
+                    prec,
+                    source,
+                    start,
+                    reverse,
+                    changed,
+                }) => {
+                    budget.step(source, *start)?;
+                    let runs = tokens.with_all(|refs| prec.runs(refs));
+                    let run = match reverse {
+                        true => runs.into_iter().rfind(|run| run.end <= *start),
+                        false => runs.into_iter().find(|run| run.start >= *start),
+                    };
+                    if let Some(run) = run {
+                        let step = climb(prec, source, tokens, budget, run)?;
+                        *start = if *reverse { step.start } else { step.start + 1 };
+                        *changed = true;
+                        return Ok(Some(step));
+                    }
+                    let changed = *changed;
+                    self.stack.pop();
+                    self.returned(changed, tokens)?;
+                    None
+                }
+                Some(Frame::Group(group)) => {
+                    // Look for the first leaf that matches, stopping early at
+                    // any other kind of child, since that has to be run by
+                    // the machine.
+                    let mut found = None;
+                    while let Some(RepTree::Leaf(seq, _, source)) = group.children.get(group.i) {
+                        found = group.worklists[group.i].first_match(
+                            seq.as_ref(),
+                            group.masks[group.i],
+                            tokens,
+                            source,
+                            budget,
+                        )?;
+                        if found.is_some() {
+                            break;
+                        }
+                        group.i += 1;
+                    }
+
+                    let children = group.children;
+                    match (found, children.get(group.i)) {
+                        (Some((start, m)), Some(RepTree::Leaf(_, trans, source))) => {
+                            let step = rewrite(trans.as_ref(), source, tokens, budget, start, m)?;
+                            group.history.record(tokens, 0, source)?;
+                            group.rewrites += 1;
+                            group.i = 0;
+                            return Ok(Some(step));
+                        }
+                        (_, Some(child)) => Some((child, false, group.reverse)),
+                        (_, None) => {
+                            budget.fixpoint(group.first, group.rewrites);
+                            let changed = group.rewrites > 0;
+                            self.stack.pop();
+                            self.returned(changed, tokens)?;
+                            None
+                        }
+                    }
+                }
+            };
+            if let Some((rep, once, reverse)) = next {
+                if let Some(step) = self.start(rep, once, reverse, tokens, budget)? {
+                    return Ok(Some(step));
+                }
+            }
+        }
+    }
+}
+
+/// Rewrites the tokens at `start` that a rule matched.
+fn rewrite<'p, 'a>(
+    trans: &dyn Transform<Vec<String>>,
+    source: &'p RuleSource,
+    tokens: &mut impl TokenStore<'a, Vec<String>>,
+    budget: &mut Budget<'_>,
+    start: usize,
+    m: Match,
+) -> Result<Step<'p>, ExecutionError> {
+    budget.rewrite(source)?;
+    let len = m.len;
+    let range = range_at(tokens, start, len);
+    // Where an empty match is, for transforms that make tokens there.
+    let site = match start {
+        s if s < tokens.len() => tokens.with_window(s, 1, |w| {
+            w.first().map(|t| (t.root, t.content_range().start))
+        }),
+        s => tokens.with_window(s.saturating_sub(1), 1, |w| {
+            w.first().map(|t| (t.root, t.content_range().end))
+        }),
+    };
+    let new_len = match site {
+        Some((root, at)) if len == 0 => {
+            tokens.replace(start, 0, |_| trans.transform_empty(root, at, &m))
+        }
+        _ => tokens.replace(start, len, |old| trans.transform_match(old, &m)),
+    };
+    budget.transformed(source, start, len, new_len);
+    Ok(Step {
+        rule: source,
+        start,
+        old_len: len,
+        new_len,
+        range,
+    })
+}
+
+/// A change to a text: `range` of the old text is replaced by `replacement`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextEdit {
+    pub range: Range<usize>,
+    pub replacement: String,
+}
+
+impl TextEdit {
+    pub fn new(range: Range<usize>, replacement: &str) -> TextEdit {
+        TextEdit {
+            range,
+            replacement: replacement.to_owned(),
+        }
+    }
+
+    /// The text after the edit.
+    pub fn apply(&self, text: &str) -> String {
+        let mut to_ret = text.to_owned();
+        to_ret.replace_range(self.range.clone(), &self.replacement);
+        to_ret
+    }
+
+    /// Whether the edit turns `old` into `new`.
+    pub fn turns(&self, old: &str, new: &str) -> bool {
+        let Range { start, end } = self.range;
+        let made = start + self.replacement.len();
+        old.get(start..end).is_some()
+            && new.get(..start) == old.get(..start)
+            && new.get(start..made) == Some(self.replacement.as_str())
+            && new.get(made..) == old.get(end..)
+    }
+}
+
+/// Where a run of a program can be cut in two. An attempt to match a rule
+/// ties together all of the text it looked at, as does a token it left, so
+/// the run can't be cut strictly inside any of the spans kept here. Anywhere
+/// else, the rules on either side never saw the other side's text, so
+/// running the program on each side gives the same tokens as the whole run.
+struct Seams {
+    /// Sorted spans that don't overlap.
+    spans: Vec<Range<usize>>,
+}
+
+impl Seams {
+    fn new(mut spans: Vec<Range<usize>>) -> Seams {
+        spans.sort_unstable_by_key(|span| span.start);
+        let mut merged: Vec<Range<usize>> = Vec::with_capacity(spans.len());
+        for span in spans {
+            match merged.last_mut() {
+                Some(last) if span.start < last.end => last.end = last.end.max(span.end),
+                _ => merged.push(span),
+            }
+        }
+        Seams { spans: merged }
+    }
+
+    /// The span that `at` is strictly inside of, if any.
+    fn around(&self, at: usize) -> Option<&Range<usize>> {
+        let after = self.spans.partition_point(|span| span.start < at);
+        self.spans[..after].last().filter(|span| at < span.end)
+    }
+
+    /// The last place at or before `at` where the run of `text` can be cut.
+    fn cut_before(&self, mut at: usize, text: &str) -> usize {
+        loop {
+            if let Some(span) = self.around(at) {
+                at = span.start;
+            }
+            if text.is_char_boundary(at) {
+                return at;
+            }
+            at -= 1;
+        }
+    }
+
+    /// The first place at or after `at` where the run of `text` can be cut.
+    fn cut_after(&self, mut at: usize, text: &str) -> usize {
+        loop {
+            if let Some(span) = self.around(at) {
+                at = span.end;
+            }
+            if at >= text.len() {
+                return text.len();
+            }
+            if text.is_char_boundary(at) {
+                return at;
+            }
+            at += 1;
+        }
+    }
+
+    /// The seams of a run that kept this one up to `start` and from `end`
+    /// on, which moved to `new_end`, and ran `window` in between.
+    fn spliced(self, start: usize, end: usize, new_end: usize, window: Seams) -> Seams {
+        let mut spans = window.spans;
+        for span in self.spans {
+            if span.end <= start {
+                spans.push(span);
+            } else if span.start >= end {
+                let hi = match span.end {
+                    usize::MAX => usize::MAX,
+                    hi => hi - end + new_end,
+                };
+                spans.push(span.start - end + new_end..hi);
+            }
+        }
+        Seams::new(spans)
+    }
+}
+
+/// Moves a token from `at` or later in the old text to the same place in
+/// `root`, where that part of the old text starts at `new_at`.
+fn rebase<'b>(
+    token: Token<'_, Vec<String>>,
+    at: usize,
+    new_at: usize,
+    root: &'b str,
+) -> Token<'b, Vec<String>> {
+    let moved = |i: usize| i - at + new_at;
+    Token {
+        root,
+        t_type: match token.t_type {
+            TokenType::Leaf(r) => TokenType::Leaf(moved(r.start)..moved(r.end)),
+            TokenType::Virtual(i, text) => TokenType::Virtual(moved(i), text),
+            TokenType::Branch(children) => TokenType::Branch(
+                children
+                    .into_iter()
+                    .map(|child| rebase(child, at, new_at, root))
+                    .collect(),
+            ),
+        },
+        data: token.data,
+        value: token.value,
+    }
+}
+
+/// The character tokens of `range` of `text`, as `char_tokens` would give
+/// them for the whole text.
+fn char_tokens_in(text: &str, range: Range<usize>) -> Vec<Token<'_, Vec<String>>> {
+    text[range.clone()]
+        .char_indices()
+        .map(|(i, c)| Token {
+            t_type: TokenType::Leaf(range.start + i..range.start + i + c.len_utf8()),
+            root: text,
+            data: char_to_token(c),
+            value: None,
+        })
+        .collect()
+}
+
+/// Turns `run` of the tokens into a single tree.
+fn climb<'p, 'a>(
+    prec: &Precedence,
+    source: &'p RuleSource,
+    tokens: &mut impl TokenStore<'a, Vec<String>>,
+    budget: &mut Budget<'_>,
+    run: Range<usize>,
+) -> Result<Step<'p>, ExecutionError> {
+    budget.matched(source, tokens, run.start, run.len());
+    let range = range_at(tokens, run.start, run.len());
+    budget.rewrite(source)?;
+    tokens.replace(run.start, run.len(), |run| vec![prec.climb(run)]);
+    budget.transformed(source, run.start, run.len(), 1);
+    Ok(Step {
+        rule: source,
+        start: run.start,
+        old_len: run.len(),
+        new_len: 1,
+        range,
+    })
+}
+
+/// The part of the text covered by the `len` tokens at `start`.
+fn text_range<'a, T: Borrow<Token<'a, Vec<String>>>>(
+    tokens: &[T],
+    start: usize,
+    len: usize,
+) -> Range<usize> {
+    match &tokens[start..start + len] {
+        [] => {
+            let at = match tokens.get(start) {
+                Some(token) => token.borrow().content_range().start,
+                None => tokens.last().map_or(0, |t| t.borrow().content_range().end),
+            };
+            at..at
+        }
+        [only] => only.borrow().content_range(),
+        [first, .., last] => {
+            first.borrow().content_range().start..last.borrow().content_range().end
+        }
+    }
+}
+
+/// Tokens along with an index of their signatures, which is kept up to date
+/// as they're replaced. Lets rules skip straight to the tokens they could
+/// start a match at.
+struct IndexedTokens<'s, S> {
+    tokens: &'s mut S,
+    index: PositionIndex,
+}
+
+impl<'s, S> IndexedTokens<'s, S> {
+    /// Indexes `tokens`, reusing `index` if it's the index of them from the
+    /// last time they were run on.
+    fn new<'a>(tokens: &'s mut S, index: PositionIndex) -> IndexedTokens<'s, S>
+    where
+        S: TokenStore<'a, Vec<String>>,
+    {
+        let index = match index.len() == tokens.len() {
+            true => index,
+            false => {
+                tokens.with_all(|refs| PositionIndex::new(refs.iter().map(|t| token_signature(t))))
+            }
+        };
+        IndexedTokens { tokens, index }
+    }
+
+    /// Whether the token at `start` shares a bit with `mask`.
+    fn admits(&self, start: usize, mask: u64) -> bool {
+        self.index.get(start).is_some_and(|bits| bits & mask != 0)
+    }
+}
+
+impl<'a, S: TokenStore<'a, Vec<String>>> TokenStore<'a, Vec<String>> for IndexedTokens<'_, S> {
+    fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    fn with_window<R>(
+        &mut self,
+        start: usize,
+        len: usize,
+        f: impl FnOnce(&[&Token<'a, Vec<String>>]) -> R,
+    ) -> R {
+        self.tokens.with_window(start, len, f)
+    }
+
+    fn replace(
+        &mut self,
+        start: usize,
+        len: usize,
+        f: impl FnOnce(Vec<Token<'a, Vec<String>>>) -> Vec<Token<'a, Vec<String>>>,
+    ) -> usize {
+        let new_len = self.tokens.replace(start, len, f);
+        let signatures = self.tokens.with_window(start, new_len, |new| {
+            new.iter().map(|t| token_signature(t)).collect::<Vec<_>>()
+        });
+        self.index.replace(start, len, signatures);
+        new_len
+    }
+}
+
+fn token_signature(token: &Token<'_, Vec<String>>) -> u64 {
+    signature(token.content(), &token.data)
+}
+
+/// The starts where a rule in a group still has to be tried. Once a start
+/// has been tried, it's known not to match until one of the tokens the
+/// attempt looked at is rewritten.
+struct Worklist {
+    /// Every start before this one has been tried, or every one from it on
+    /// if the group is reversed.
+    frontier: usize,
+    /// Starts on the tried side of `frontier` to try again, since the tokens
+    /// around them changed.
+    dirty: BTreeSet<usize>,
+    /// The most tokens any failed attempt has looked at.
+    longest: usize,
+    reverse: bool,
+}
+
+impl Worklist {
+    fn new(len: usize, reverse: bool) -> Worklist {
+        Worklist {
+            frontier: if reverse { len } else { 0 },
+            dirty: BTreeSet::new(),
+            longest: 0,
+            reverse,
+        }
+    }
+
+    /// Takes the next start to try off the list: the first one, or the last
+    /// one if the group is reversed. The frontier jumps straight to the next
+    /// token that shares a bit with `mask`, since the rule can't match at
+    /// the ones in between.
+    fn pop(&mut self, index: &PositionIndex, mask: u64) -> Option<usize> {
+        // Dirty starts are always on the near side of the frontier.
+        let dirty = match self.reverse {
+            true => self.dirty.pop_last(),
+            false => self.dirty.pop_first(),
+        };
+        if dirty.is_some() {
+            return dirty;
+        }
+        let next = match self.reverse {
+            true => index.prev(self.frontier, mask),
+            false => index.next(self.frontier, mask),
+        };
+        self.frontier = match (next, self.reverse) {
+            (Some(start), true) => start,
+            (Some(start), false) => start + 1,
+            (None, true) => 0,
+            (None, false) => self.frontier.max(index.len()),
+        };
+        next
+    }
+
+    fn tried(&self, start: usize) -> bool {
+        match self.reverse {
+            true => start >= self.frontier,
+            false => start < self.frontier,
+        }
+    }
+
+    /// Finds the first start where `seq` matches, or the last one if the
+    /// group is reversed, returning it along with the match.
+    fn first_match<'a>(
+        &mut self,
+        seq: &dyn Sequence<Vec<String>>,
+        mask: u64,
+        tokens: &mut IndexedTokens<'_, impl TokenStore<'a, Vec<String>>>,
+        rule: &RuleSource,
+        budget: &mut Budget<'_>,
+    ) -> Result<Option<(usize, Match)>, ExecutionError> {
+        while let Some(start) = self.pop(&tokens.index, mask) {
+            let span = if tokens.admits(start, mask) {
+                budget.step(rule, start)?;
+                let (found, span) = match_at(seq, tokens, start);
+                budget.looked(tokens, start, span);
+                if let Some(m) = found {
+                    budget.matched(rule, tokens, start, m.len);
+                    return Ok(Some((start, m)));
+                }
+                span
+            } else {
+                1
+            };
+            self.longest = self.longest.max(span);
+        }
+        Ok(None)
+    }
+
+    /// Accounts for `old_len` tokens at `start` being replaced by `new_len`
+    /// others. The new tokens, and the tried starts whose attempts may have
+    /// looked at the old ones, have to be tried again.
+    fn rewrite(&mut self, start: usize, old_len: usize, new_len: usize) {
+        let end = start + old_len;
+        let after = self.dirty.split_off(&start);
+        self.dirty.extend(
+            after
+                .into_iter()
+                .filter(|&s| s >= end)
+                .map(|s| s - old_len + new_len),
+        );
+        let new = start..start + new_len;
+        if self.frontier >= end {
+            self.frontier = self.frontier - old_len + new_len;
+        } else if self.frontier > start {
+            // The frontier was among the old tokens, so none of the new ones
+            // have been tried.
+            self.frontier = if self.reverse { new.end } else { start };
+        }
+        if self.tried(start) {
+            self.dirty.extend(new);
+        }
+        let before = start.saturating_sub(self.longest)..start;
+        let before = before.filter(|&s| self.tried(s)).collect::<Vec<_>>();
+        self.dirty.extend(before);
+    }
+}
+
+impl Display for RepTree {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), std::fmt::Error> {
+        match self {
+            RepTree::Branch(children) => write!(
+                fmt,
+                "{}",
+                children
+                    .iter()
+                    .map(|rt| format!("{rt}"))
+                    .reduce(|acc, i| acc + &i)
+                    .unwrap_or(String::new())
+            ),
+            RepTree::Leaf(_, _, _) => write!(fmt, "sequence"),
+            RepTree::Once(r) => write!(fmt, "%{r}"),
+            RepTree::Reverse(r) => write!(fmt, "<{r}"),
+            RepTree::Precedence(_, _) => write!(fmt, "precedence"),
+            RepTree::Lexer(lexer) => write!(fmt, "%{}", "sequence".repeat(lexer.rules.len())),
+        }
+    }
+}
+
+impl Debug for RepTree {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), std::fmt::Error> {
+        write!(fmt, "{self}")
+    }
+}
+
+/// The tokens a program starts out with: one for each character of `text`,
+/// tagged by `char_to_token`.
+pub fn char_tokens(text: &str) -> Vec<Token<'_, Vec<String>>> {
+    Token::token_vec_from_str(text, |r, i| char_to_token(r[i..].chars().next().unwrap()))
+}
+
+pub fn char_to_token(c: char) -> Vec<String> {
+    let mut to_ret = vec![c.to_string(), "u".to_owned() + &(c as u32).to_string()];
+
+    if c.is_whitespace() || c.is_control() {
+        to_ret.push("ws".to_string());
+    }
 
     to_ret
 }
 
 pub fn prog_from_str(text: &str) -> Option<SeqProg> {
-    create_program(tuck_tokens(text))
+    compile_program(text, &TransformRegistry::new()).ok()
+}
+
+/// Compiles a program, resolving `rule => name;` against `registry`.
+pub fn compile_program(text: &str, registry: &TransformRegistry) -> Result<SeqProg, CompileError> {
+    create_program(tuck_tokens(text), registry)
+}
+
+/// Like `compile_program`, but finds the files the program imports with
+/// `resolver`.
+pub fn compile_program_with(
+    text: &str,
+    registry: &TransformRegistry,
+    resolver: &dyn GrammarResolver,
+) -> Result<SeqProg, CompileError> {
+    create_program_in(tuck_tokens(text), registry, &mut Importer::new(resolver))
+}
+
+/// Finds the text of the grammars that programs import, with
+/// `import "path";` or `import name, ... from "path";`.
+pub trait GrammarResolver {
+    fn resolve(&self, path: &str) -> io::Result<String>;
+}
+
+/// Grammars kept in memory, by path.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryResolver {
+    files: HashMap<String, String>,
+}
+
+impl MemoryResolver {
+    pub fn new() -> MemoryResolver {
+        MemoryResolver::default()
+    }
+
+    pub fn insert(&mut self, path: &str, text: &str) -> &mut MemoryResolver {
+        self.files.insert(path.to_owned(), text.to_owned());
+        self
+    }
+}
+
+impl GrammarResolver for MemoryResolver {
+    fn resolve(&self, path: &str) -> io::Result<String> {
+        self.files
+            .get(path)
+            .cloned()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no such grammar"))
+    }
+}
+
+/// Grammars read from files, with paths relative to `root`.
+#[derive(Debug, Clone)]
+pub struct FileResolver {
+    pub root: PathBuf,
+}
+
+impl FileResolver {
+    pub fn new(root: impl Into<PathBuf>) -> FileResolver {
+        FileResolver { root: root.into() }
+    }
+}
+
+impl GrammarResolver for FileResolver {
+    fn resolve(&self, path: &str) -> io::Result<String> {
+        fs::read_to_string(self.root.join(path))
+    }
+}
+
+/// Compiles the grammars a program imports, keeping track of the ones it's in
+/// the middle of, so that a grammar can't end up importing itself.
+struct Importer<'r> {
+    resolver: &'r dyn GrammarResolver,
+    importing: Vec<String>,
+    /// Each grammar compiled so far, so that one imported by several others
+    /// is only compiled once. Its rules are kept until something imports the
+    /// whole grammar, and only the first such import gets them.
+    imported: HashMap<String, (Definitions, Option<SeqProg>)>,
+}
+
+impl<'r> Importer<'r> {
+    fn new(resolver: &'r dyn GrammarResolver) -> Importer<'r> {
+        Importer {
+            resolver,
+            importing: vec![],
+            imported: HashMap::new(),
+        }
+    }
+
+    /// Compiles the grammar `token` imports, unless it already has been,
+    /// adding the definitions it asks for to `defs`. If it imports the whole
+    /// grammar, gives the rest of it as well, the first time.
+    fn import(
+        &mut self,
+        token: &Token<Vec<&str>>,
+        registry: &TransformRegistry,
+        defs: &mut Definitions,
+    ) -> Result<Option<SeqProg>, CompileError> {
+        let error = |kind| CompileError::new(kind, token);
+        let children = token
+            .children()
+            .ok_or_else(|| error(CompileErrorKind::Malformed))?;
+        let path = children[children.len() - 2].content();
+        let path = &path[1..path.len() - 1];
+        if self.importing.iter().any(|p| p == path) {
+            return Err(error(CompileErrorKind::ImportCycle(path.to_owned())));
+        }
+        if !self.imported.contains_key(path) {
+            let text = self.resolver.resolve(path).map_err(|e| {
+                error(CompileErrorKind::ImportFailed(
+                    path.to_owned(),
+                    e.to_string(),
+                ))
+            })?;
+
+            self.importing.push(path.to_owned());
+            let sub = create_program_in(tuck_tokens(&text), registry, self);
+            self.importing.pop();
+            let mut sub = sub.map_err(|e| e.in_file(path))?;
+            let sub_defs = std::mem::take(&mut sub.defs);
+            self.imported.insert(path.to_owned(), (sub_defs, Some(sub)));
+        }
+        let (sub_defs, rules) = self.imported.get_mut(path).unwrap();
+
+        // Between `import` and `from`, if it's there.
+        let names = children
+            .get(1..children.len() - 3)
+            .unwrap_or_default()
+            .iter()
+            .filter(|c| c.data.contains(&"word"))
+            .collect::<Vec<_>>();
+        if names.is_empty() {
+            for (name, def) in sub_defs.iter() {
+                add_import(defs, name, def, token)?;
+            }
+            return Ok(rules.take());
+        }
+        for name in names {
+            let def = sub_defs.get(name.content()).ok_or_else(|| {
+                CompileError::new(
+                    CompileErrorKind::NotDefinedIn(name.content().to_owned(), path.to_owned()),
+                    name,
+                )
+            })?;
+            add_import(defs, name.content(), def, name)?;
+        }
+        Ok(None)
+    }
+}
+
+/// Adds an imported definition to `defs`, unless a different one with the
+/// same name is already there. The same one can come in twice, from a
+/// grammar that two others import.
+fn add_import(
+    defs: &mut Definitions,
+    name: &str,
+    def: &Arc<SyncSequence<Vec<String>>>,
+    token: &Token<Vec<&str>>,
+) -> Result<(), CompileError> {
+    match defs.get(name) {
+        Some(old) if !Arc::ptr_eq(old, def) => Err(CompileError::new(
+            CompileErrorKind::Redefined(name.to_owned()),
+            token,
+        )),
+        _ => {
+            defs.insert(name.to_owned(), Arc::clone(def));
+            Ok(())
+        }
+    }
+}
+
+/// Gives tokens a semantic value by running a converter on their content,
+/// declared with `=> value(name)`. If `inner` is set, every token it returns
+/// is converted, and otherwise the marked tokens (or all of them) are
+/// converted where they are. A token whose content can't be converted gets a
+/// `ConversionError` as its value instead, which `SeqProg::eval` reports.
+pub struct ValueTransform {
+    pub inner: Option<Box<SyncTransform<Vec<String>>>>,
+    pub name: String,
+    pub convert: Arc<Converter>,
+}
+
+impl ValueTransform {
+    fn convert_token(&self, token: &mut Token<Vec<String>>) {
+        token.value = Some(match (self.convert)(token.content()) {
+            Ok(value) => value,
+            Err(message) => Box::new(ConversionError {
+                converter: self.name.clone(),
+                message,
+            }),
+        });
+    }
+}
+
+impl Transform<Vec<String>> for ValueTransform {
+    fn transform<'a>(&self, tokens: Vec<Token<'a, Vec<String>>>) -> Vec<Token<'a, Vec<String>>> {
+        let len = tokens.len();
+        self.transform_match(tokens, &Match::whole(len))
+    }
+
+    fn transform_match<'a>(
+        &self,
+        tokens: Vec<Token<'a, Vec<String>>>,
+        m: &Match,
+    ) -> Vec<Token<'a, Vec<String>>> {
+        match &self.inner {
+            Some(inner) => {
+                let mut new_tox = inner.transform_match(tokens, m);
+                new_tox.iter_mut().for_each(|t| self.convert_token(t));
+                new_tox
+            }
+            None => {
+                let mut tokens = tokens;
+                for i in m.marked() {
+                    if let Some(t) = tokens.get_mut(i) {
+                        self.convert_token(t);
+                    }
+                }
+                tokens
+            }
+        }
+    }
+
+    fn transform_empty<'a>(
+        &self,
+        root: &'a str,
+        at: usize,
+        m: &Match,
+    ) -> Vec<Token<'a, Vec<String>>> {
+        let mut new_tox = match &self.inner {
+            Some(inner) => inner.transform_empty(root, at, m),
+            None => vec![],
+        };
+        new_tox.iter_mut().for_each(|t| self.convert_token(t));
+        new_tox
+    }
+}
+
+/// The value of a token that a converter couldn't read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConversionError {
+    pub converter: String,
+    pub message: String,
+}
+
+/// Reports every `ConversionError` in `tokens` and their descendants.
+fn conversion_errors(tokens: &[Token<Vec<String>>], diagnostics: &mut Vec<Diagnostic>) {
+    for token in tokens {
+        if let Some(err) = token.value::<ConversionError>() {
+            diagnostics.push(Diagnostic::new(
+                format!(
+                    "couldn't read `{}` as {}: {}",
+                    token.content(),
+                    err.converter,
+                    err.message
+                ),
+                token.root,
+                token.content_range().start,
+            ));
+        }
+        if let Some(children) = token.children() {
+            conversion_errors(children, diagnostics);
+        }
+    }
+}
+
+/// Reads a semantic value out of a token's content.
+pub type Converter = dyn Fn(&str) -> Result<Value, String> + Send + Sync;
+
+/// Transforms that programs can refer to by name, with `rule => name;`, and
+/// converters they can refer to with `=> value(name)`.
+#[derive(Clone)]
+pub struct TransformRegistry {
+    transforms: HashMap<String, Arc<SyncTransform<Vec<String>>>>,
+    converters: HashMap<String, Arc<Converter>>,
+}
+
+impl Default for TransformRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TransformRegistry {
+    /// A registry with no transforms, and converters for `i64`, `u64`, `f64`,
+    /// `bool` and `String`.
+    pub fn new() -> TransformRegistry {
+        let mut registry = TransformRegistry {
+            transforms: HashMap::new(),
+            converters: HashMap::new(),
+        };
+        registry
+            .register_value("i64", str::parse::<i64>)
+            .register_value("u64", str::parse::<u64>)
+            .register_value("f64", str::parse::<f64>)
+            .register_value("bool", str::parse::<bool>)
+            .register_value("String", |s| Ok::<_, String>(s.to_owned()));
+        registry
+    }
+
+    /// Registers `convert` as the converter called `name`. If it fails, the
+    /// error becomes a diagnostic.
+    pub fn register_value<V, E, F>(&mut self, name: &str, convert: F) -> &mut TransformRegistry
+    where
+        V: Any + Send + Sync,
+        E: Display,
+        F: Fn(&str) -> Result<V, E> + Send + Sync + 'static,
+    {
+        self.converters.insert(
+            name.to_owned(),
+            Arc::new(move |s| match convert(s) {
+                Ok(v) => Ok(Box::new(v) as Value),
+                Err(e) => Err(e.to_string()),
+            }),
+        );
+        self
+    }
+
+    pub fn converter(&self, name: &str) -> Option<Arc<Converter>> {
+        self.converters.get(name).cloned()
+    }
+
+    pub fn register(
+        &mut self,
+        name: &str,
+        transform: impl Transform<Vec<String>> + Send + Sync + 'static,
+    ) -> &mut TransformRegistry {
+        self.transforms.insert(name.to_owned(), Arc::new(transform));
+        self
+    }
+
+    pub fn register_fn<F>(&mut self, name: &str, func: F) -> &mut TransformRegistry
+    where
+        F: for<'a> Fn(Vec<Token<'a, Vec<String>>>) -> Vec<Token<'a, Vec<String>>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.register(name, FnTransform::new(func))
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<SyncTransform<Vec<String>>>> {
+        self.transforms.get(name).cloned()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CompileErrorKind {
+    /// Part of the program couldn't be understood.
+    Malformed,
+    /// A rule refers to a transform that isn't in the registry.
+    UnknownTransform(String),
+    /// A rule refers to a converter that isn't in the registry.
+    UnknownConverter(String),
+    /// A retagging rule has a tag without a `+` or `-` in front of it.
+    NotATagChange(String),
+    /// A rule uses `$name`, but there's no `def name = ...;`.
+    UndefinedName(String),
+    /// There's more than one `def` with the same name.
+    Redefined(String),
+    /// A definition uses itself, through the other definitions listed after
+    /// it, if any.
+    DefinitionCycle(Vec<String>),
+    /// An imported grammar couldn't be found or read, for the given reason.
+    ImportFailed(String, String),
+    /// A grammar ends up importing itself.
+    ImportCycle(String),
+    /// A name is imported from a grammar that doesn't define it.
+    NotDefinedIn(String, String),
+    /// An `in` group, or a rule that switches modes, outside of a `%{ }`
+    /// pass.
+    ModeOutsidePass,
+    /// Something other than a rule or an `in` group in a `%{ }` pass that
+    /// uses modes.
+    NotALexerRule,
+}
+
+/// A problem with the text of a program, located in that text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompileError {
+    pub kind: CompileErrorKind,
+    /// The imported grammar the problem is in, or `None` if it's in the
+    /// program itself.
+    pub file: Option<String>,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl CompileError {
+    pub fn new(kind: CompileErrorKind, at: &Token<Vec<&str>>) -> CompileError {
+        let location = Diagnostic::new(String::new(), at.root, at.content_range().start);
+        CompileError {
+            kind,
+            file: None,
+            line: location.line,
+            column: location.column,
+        }
+    }
+
+    /// Places the error in `file`, unless it's already in a file that `file`
+    /// imports.
+    fn in_file(mut self, file: &str) -> CompileError {
+        self.file.get_or_insert_with(|| file.to_owned());
+        self
+    }
+}
+
+impl Display for CompileError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.kind {
+            CompileErrorKind::Malformed => write!(f, "couldn't understand this rule")?,
+            CompileErrorKind::UnknownTransform(name) => write!(f, "unknown transform `{name}`")?,
+            CompileErrorKind::UnknownConverter(name) => write!(f, "unknown converter `{name}`")?,
+            CompileErrorKind::NotATagChange(tag) => {
+                write!(f, "`{tag}` needs a `+` or `-` in front of it")?
+            }
+            CompileErrorKind::UndefinedName(name) => write!(f, "undefined name `${name}`")?,
+            CompileErrorKind::Redefined(name) => write!(f, "`{name}` is defined more than once")?,
+            CompileErrorKind::DefinitionCycle(names) => match names.split_first() {
+                Some((name, [])) => write!(f, "`{name}` is defined in terms of itself")?,
+                Some((name, through)) => write!(
+                    f,
+                    "`{name}` is defined in terms of itself through `{}`",
+                    through.join("`, `")
+                )?,
+                None => write!(f, "a definition is defined in terms of itself")?,
+            },
+            CompileErrorKind::ImportFailed(path, reason) => {
+                write!(f, "couldn't import `{path}`: {reason}")?
+            }
+            CompileErrorKind::ImportCycle(path) => write!(f, "`{path}` ends up importing itself")?,
+            CompileErrorKind::NotDefinedIn(name, path) => {
+                write!(f, "`{path}` doesn't define `{name}`")?
+            }
+            CompileErrorKind::ModeOutsidePass => {
+                write!(f, "modes can only be used in a `%{{ }}` pass")?
+            }
+            CompileErrorKind::NotALexerRule => write!(
+                f,
+                "a `%{{ }}` pass with modes can only have rules and `in` groups"
+            )?,
+        }
+        match &self.file {
+            Some(file) => write!(f, " at {file}:{}:{}", self.line, self.column),
+            None => write!(f, " at {}:{}", self.line, self.column),
+        }
+    }
 }
 
 pub fn eval_prog_from_text<'a>(prog: &str, text: &'a str) -> Vec<Token<'a, Vec<String>>> {
     let sp = prog_from_str(prog).unwrap();
-    let mut tox = Token::token_vec_from_str(text, |r, i| char_to_token(r.chars().nth(i).unwrap()));
-    sp.execute(&mut tox);
-    tox
+    sp.eval(text).tokens
+}
+
+/// The tokens left over after running a program, along with anything that
+/// went wrong along the way.
+pub struct ParseResult<'a> {
+    pub tokens: Vec<Token<'a, Vec<String>>>,
+    pub diagnostics: Vec<Diagnostic>,
+    /// Where `SeqProg::reparse` can cut the run, if it was kept track of.
+    seams: Option<Seams>,
+}
+
+// How the result was made doesn't change what it is.
+impl Debug for ParseResult<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ParseResult")
+            .field("tokens", &self.tokens)
+            .field("diagnostics", &self.diagnostics)
+            .finish()
+    }
+}
+
+/// Like `eval_prog_from_text`, but if the text doesn't reduce to a single
+/// token, also explains where the program got stuck.
+pub fn eval_prog_with_diagnostics<'a>(prog: &str, text: &'a str) -> ParseResult<'a> {
+    prog_from_str(prog).unwrap().eval(text)
 }
 
 pub fn graph_with_tags(tokens: &Vec<Token<Vec<String>>>) {
@@ -68,6 +68,910 @@ use test_case::test_case;
 #[test_case("
 %'a' & ('b' & 'a')+. foo;
 ", "a aba ababa"; "one or more in parens sequence")]
+#[test_case("
+%'a' +foo -ws;
+", "a"; "sugared retag")]
 pub fn eval_simple_prog(prog: &str, text: &str) {
     graph_with_tags(&eval_prog_from_text(prog, text));
 }
+
+pub fn tags_of(tokens: &[Token<Vec<String>>]) -> Vec<Vec<String>> {
+    tokens.iter().map(|t| t.data.clone()).collect()
+}
+
+#[test_case("
+%'a' +foo;
+", "ab", vec![vec!["a", "u97", "foo"], vec!["b", "u98"]]; "adding a tag")]
+#[test_case("
+%'a' +foo -a;
+", "a", vec![vec!["u97", "foo"]]; "adding and removing tags")]
+#[test_case("
+%'-' & @'1' +negated;
+", "1-1", vec![vec!["1", "u49"], vec!["-", "u45"], vec!["1", "u49", "negated"]]; "retagging marked tokens")]
+#[test_case("
+% {
+    {.let.}. let;
+    a..z+. word;
+    ws~;
+}
+{
+    let & @word +type_name;
+}
+", "let x y", vec![vec!["let"], vec!["word", "type_name"], vec!["word"]]; "retagging in a group")]
+pub fn retag_test(prog: &str, text: &str, expected: Vec<Vec<&str>>) {
+    assert_eq!(tags_of(&eval_prog_from_text(prog, text)), expected);
+}
+
+#[test_case("%'a' +b c;", "`c` needs a `+` or `-` in front of it at 1:9"; "a stray tag")]
+#[test_case("%'a' -b +c d -e;", "`d` needs a `+` or `-` in front of it at 1:12"; "a stray tag among others")]
+pub fn retag_error_test(prog: &str, expected: &str) {
+    let err = compile_program(prog, &TransformRegistry::new()).unwrap_err();
+    assert_eq!(err.to_string(), expected);
+}
+
+#[test_case("
+%'a' & 'b': ab;
+%ab ^;
+", "ab", vec![vec!["a", "u97"], vec!["b", "u98"]]; "flattening")]
+#[test_case("
+%'(' & 'a' & ')': parens;
+%parens ^ a;
+", "(a)", vec![vec!["a", "u97"]]; "unwrapping")]
+#[test_case("
+%'(' & 'a' & ')': parens;
+%parens ^^ a;
+", "(a)", vec![vec!["a", "u97", "parens"]]; "unwrapping and merging tags")]
+#[test_case("
+%'a' +x;
+{
+    '(' & x & ')': parens, x;
+    parens ^^ x;
+}
+", "((a))", vec![vec!["a", "u97", "x", "parens"]]; "unwrapping in a group")]
+pub fn unwrap_test(prog: &str, text: &str, expected: Vec<Vec<&str>>) {
+    assert_eq!(tags_of(&eval_prog_from_text(prog, text)), expected);
+}
+
+#[test_case("
+%'(' & 'a' & ')': parens [1];
+", "(a)", "{\n\ta\n}\n", "a"; "selecting by index")]
+#[test_case("
+%'a' & 'b' & 'c': abc [2, 0];
+", "abc", "{\n\tc\n\ta\n}\n", "abc"; "selecting out of order")]
+#[test_case("
+%'a' & second@'b' & first@'c': abc [first, second];
+", "abc", "{\n\tc\n\tb\n}\n", "bc"; "selecting by capture")]
+#[test_case("
+%'a' & item@'b'+ & ';': list [item];
+", "abb;", "{\n\tb\n\tb\n}\n", "bb"; "selecting a repeated capture")]
+pub fn select_test(prog: &str, text: &str, expected: &str, content: &str) {
+    let tox = eval_prog_from_text(prog, text);
+    assert_eq!(tox.len(), 1);
+    assert_eq!(tox[0].graph(), expected);
+    assert_eq!(tox[0].content(), content);
+}
+
+#[test]
+pub fn select_empty_test() {
+    let tox = eval_prog_from_text("%'(' & 'a'? & ')': parens [1];", "()");
+    assert_eq!(tox.len(), 1);
+    assert_eq!(tox[0].data, vec!["parens"]);
+    assert!(tox[0].children().is_none());
+    assert_eq!(tox[0].content(), "");
+}
+
+#[test]
+pub fn select_unknown_capture_test() {
+    assert!(prog_from_str("%'a' & 'b': ab [nope];").is_none());
+}
+
+pub fn test_registry() -> TransformRegistry {
+    let mut registry = TransformRegistry::new();
+    registry
+        .register_fn("swap", |mut tokens| {
+            tokens.reverse();
+            tokens
+        })
+        .register(
+            "pair",
+            DeepTransform {
+                data: vec!["pair".to_string()],
+            },
+        );
+    registry
+}
+
+#[test_case("
+%'a' & 'b' => swap;
+", "abab", "baba"; "registered closure")]
+#[test_case("
+%'a' & 'b' => pair;
+%pair & pair => swap;
+", "abab", "abab"; "registered transform")]
+pub fn registry_test(prog: &str, text: &str, expected: &str) {
+    let sp = compile_program(prog, &test_registry()).unwrap();
+    assert_eq!(Token::vec_content(&sp.eval(text).tokens), expected);
+}
+
+#[test_case("
+%'a' => nope;
+", "unknown transform `nope` at 2:9"; "unknown transform")]
+#[test_case("
+{
+    'a'. a;
+    'b' => nope;
+}
+", "unknown transform `nope` at 4:12"; "unknown transform in a group")]
+pub fn registry_error_test(prog: &str, expected: &str) {
+    let err = compile_program(prog, &test_registry()).unwrap_err();
+    assert_eq!(err.to_string(), expected);
+}
+
+#[test_case("
+%'|' & !';' => insert(';'), semi;
+", "a|b|;c", "a|;b|;c", vec![vec!["a", "u97"], vec!["|", "u124"], vec!["semi"], vec!["b", "u98"], vec!["|", "u124"], vec![";", "u59"], vec!["c", "u99"]]; "inserting where missing")]
+#[test_case("
+{
+    'a' & !end => insert, end;
+}
+", "aa", "aa", vec![vec!["a", "u97"], vec!["end"], vec!["a", "u97"], vec!["end"]]; "inserting without text")]
+#[test_case("
+%!'b' => insert(';');
+", "ab", ";ab", vec![vec![], vec!["a", "u97"], vec!["b", "u98"]]; "inserting at the start")]
+#[test_case("
+%!'b' : empty;
+", "ab", "ab", vec![vec!["empty"], vec!["a", "u97"], vec!["b", "u98"]]; "tagging an empty match")]
+#[test_case("
+!'b'~;
+'a'~;
+", "ab", "b", vec![vec!["b", "u98"]]; "removing an empty match")]
+pub fn insert_test(prog: &str, text: &str, expected: &str, expected_tags: Vec<Vec<&str>>) {
+    let tox = eval_prog_from_text(prog, text);
+    assert_eq!(Token::vec_content(&tox), expected);
+    assert_eq!(tags_of(&tox), expected_tags);
+}
+
+#[test_case("
+%'>' & '>'. shr;
+%'<' & a..z & @shr / gt / 1 gt;
+", "<a>>", vec![vec!["<", "u60"], vec!["a", "u97"], vec!["gt"], vec!["gt"]], "<a>>"; "splitting marked tokens")]
+#[test_case("
+%a..z+. word;
+%word / keyword / 3 word;
+", "letx", vec![vec!["keyword"], vec!["word"]], "letx"; "splitting a word")]
+#[test_case("
+%a..z+. word;
+%word / keyword / 3 word;
+", "ab", vec![vec!["word"]], "ab"; "leaving short tokens alone")]
+pub fn split_test(prog: &str, text: &str, expected: Vec<Vec<&str>>, expected_content: &str) {
+    let tox = eval_prog_from_text(prog, text);
+    assert_eq!(tags_of(&tox), expected);
+    assert_eq!(Token::vec_content(&tox), expected_content);
+}
+
+#[test_case("
+%0..9+. int => value(i64);
+", "42", Some(42); "converting a new token")]
+#[test_case("
+%0..9+. int;
+%int => value(i64);
+", "42", Some(42); "converting in place")]
+#[test_case("
+%0..9+. int;
+", "42", None; "no conversion")]
+pub fn value_test(prog: &str, text: &str, expected: Option<i64>) {
+    let tox = eval_prog_from_text(prog, text);
+    assert_eq!(tox[0].value::<i64>().copied(), expected);
+}
+
+#[test]
+pub fn value_registry_test() {
+    let mut registry = TransformRegistry::new();
+    registry.register_value("len", |s: &str| Ok::<_, String>(s.len()));
+    let sp = compile_program("%a..z+. word => value(len);", &registry).unwrap();
+    let res = sp.eval("abc");
+    assert_eq!(res.tokens[0].value::<usize>(), Some(&3));
+}
+
+#[test_case("
+%0..9+. int => value(i64);
+", "99999999999999999999", vec!["couldn't read `99999999999999999999` as i64: number too large to fit in target type at 1:1"]; "overflow")]
+#[test_case("
+%0..9+. int => value(i64);
+%'-' & int: neg => value(i64);
+", "-1", vec![]; "no errors")]
+#[test_case("
+%0..9+. int => value(i64);
+%ws~;
+%'-' & int: neg => value(i64);
+", "- 1", vec!["couldn't read `- 1` as i64: invalid digit found in string at 1:1"]; "converting a branch")]
+pub fn value_error_test(prog: &str, text: &str, expected: Vec<&str>) {
+    let res = eval_prog_with_diagnostics(prog, text);
+    let messages = res
+        .diagnostics
+        .iter()
+        .map(|d| d.to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(messages, expected);
+}
+
+#[test]
+pub fn unknown_converter_test() {
+    let err = compile_program("\n%'a' => value(nope);", &TransformRegistry::new()).unwrap_err();
+    assert_eq!(err.to_string(), "unknown converter `nope` at 2:15");
+}
+
+const LAYOUT_PROG: &str = "
+layout indent;
+%ws~;
+%a..z. word;
+";
+
+#[test_case("a\n  b\nc", vec!["word", "newline", "indent", "word", "newline", "dedent", "word", "newline"]; "indenting and dedenting")]
+#[test_case("a\n  b\n\n    c\n", vec!["word", "newline", "indent", "word", "newline", "indent", "word", "newline", "dedent", "dedent"]; "closing levels at the end")]
+#[test_case("a\n\t \nb", vec!["word", "newline", "word", "newline"]; "skipping blank lines")]
+pub fn layout_test(text: &str, expected: Vec<&str>) {
+    let tox = eval_prog_from_text(LAYOUT_PROG, text);
+    let tags = tox.iter().map(|t| t.data[0].as_str()).collect::<Vec<_>>();
+    assert_eq!(tags, expected);
+}
+
+#[test]
+pub fn layout_error_test() {
+    let res = eval_prog_with_diagnostics(LAYOUT_PROG, "a\n    b\n  c");
+    let messages = res
+        .diagnostics
+        .iter()
+        .map(|d| d.to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(messages, vec!["inconsistent dedent at 3:3"]);
+}
+
+#[test_case("
+%'1'. int, expr;
+%'(' & expr & ')': parens, inherit(1);
+", "(1)", vec!["parens", "int", "expr"]; "inheriting by index")]
+#[test_case("
+%'1'. int, expr;
+%'(' & expr & ')': parens, inherit(expr);
+", "(1)", vec!["parens", "int", "expr"]; "inheriting by tag")]
+#[test_case("
+%'1'. int, expr;
+%'(' & inner@expr & ')': parens, inherit(inner);
+", "(1)", vec!["parens", "int", "expr"]; "inheriting by capture")]
+#[test_case("
+%'1'. int, expr;
+%'(' & expr & ')'. parens, inherit(1);
+", "(1)", vec!["parens", "int", "expr"]; "inheriting in a shallow transform")]
+#[test_case("
+%'1'. int, expr;
+{
+    '(' & expr & ')': inherit(1), parens;
+}
+", "((1))", vec!["parens", "int", "expr"]; "inheriting through nested parens")]
+pub fn inherit_test(prog: &str, text: &str, expected: Vec<&str>) {
+    let tox = eval_prog_from_text(prog, text);
+    assert_eq!(tags_of(&tox), vec![expected]);
+}
+
+fn limited_eval(prog: &str, text: &str, limits: ExecutionLimits) -> Result<usize, String> {
+    let prog = compile_program(prog, &TransformRegistry::new()).unwrap();
+    prog.eval_with_limits(text, &limits)
+        .map(|result| result.tokens.len())
+        .map_err(|err| err.to_string())
+}
+
+#[test_case("{ a . b; b . a; }", "a", "went around in circles in rule `a . b;` at 1:3"; "retagging back and forth")]
+#[test_case("{\n  'x' | 'a'?~;\n  'a' . b;\n  'b' . a;\n}", "b", "went around in circles in rule `'b' . a;` at 4:3"; "retagging text back and forth")]
+pub fn cycle_test(prog: &str, text: &str, expected: &str) {
+    let limits = ExecutionLimits {
+        detect_cycles: true,
+        ..Default::default()
+    };
+    assert_eq!(limited_eval(prog, text, limits), Err(expected.to_owned()));
+}
+
+#[test_case("{\n  'x' | 'a'?~;\n}"; "removing nothing in a group")]
+#[test_case("%'a'?~;"; "removing nothing in a single pass")]
+#[test_case("'a'?~;"; "removing nothing")]
+pub fn remove_nothing_test(prog: &str) {
+    let limits = ExecutionLimits {
+        detect_cycles: true,
+        ..Default::default()
+    };
+    assert_eq!(limited_eval(prog, "b", limits), Ok(1));
+}
+
+#[test]
+pub fn rewrite_limit_test() {
+    let prog = "{ 'a' ~; }";
+    let limits = |max| ExecutionLimits {
+        max_rewrites: Some(max),
+        ..Default::default()
+    };
+    assert_eq!(limited_eval(prog, "aaab", limits(3)), Ok(1));
+    assert_eq!(
+        limited_eval(prog, "aaab", limits(2)),
+        Err("ran out of rewrites in rule `'a' ~;` at 1:3".to_owned())
+    );
+}
+
+#[test]
+pub fn step_limit_test() {
+    let limits = ExecutionLimits {
+        max_steps: Some(10),
+        ..Default::default()
+    };
+    assert_eq!(
+        limited_eval("'b' & 'a' ~;", "bbbbbbbbbbbb", limits),
+        Err("ran out of steps in rule `'b' & 'a' ~;` at 1:1".to_owned())
+    );
+}
+
+#[test_case("'(' & 'a' & ')' : p;", 14, 1; "a top-level rule")]
+#[test_case("<'(' & 'a' & ')' : p;", 14, 1; "a reversed rule")]
+#[test_case("%'(' & 'a' & ')' : p;", 12, 2; "a pass")]
+#[test_case("<%'(' & 'a' & ')' : p;", 12, 2; "a reversed pass")]
+#[test_case("{ 'x' & 'y' . q; '(' & 'a' & ')' : p; }", 12, 2; "a group")]
+#[test_case("%{ in main { '(' & 'a' & ')' : p; } }", 12, 2; "a lexer")]
+pub fn skip_test(prog: &str, len: usize, tries: usize) {
+    let prog = compile_program(prog, &TransformRegistry::new()).unwrap();
+    let mut tracer = LogTracer::new(vec![]);
+    let result = prog
+        .eval_traced("xx(a)xxxxxx(a)xx", &ExecutionLimits::default(), &mut tracer)
+        .unwrap();
+    assert_eq!(result.tokens.len(), len);
+    let log = String::from_utf8(tracer.out).unwrap();
+    let tried = log.lines().filter(|l| l.contains("try `'('")).count();
+    assert_eq!(tried, tries, "{log}");
+}
+
+#[test]
+pub fn deadline_test() {
+    let limits = ExecutionLimits {
+        deadline: Some(std::time::Instant::now()),
+        ..Default::default()
+    };
+    assert_eq!(
+        limited_eval("'b' . c;", "b", limits),
+        Err("ran past the deadline in rule `'b' . c;` at 1:1".to_owned())
+    );
+}
+
+#[test]
+pub fn log_tracer_test() {
+    let prog =
+        compile_program("{ ab . done; 'a' & 'b' : ab; }", &TransformRegistry::new()).unwrap();
+    let mut tracer = LogTracer::new(vec![]);
+    let result = prog
+        .eval_traced("ab", &ExecutionLimits::default(), &mut tracer)
+        .unwrap();
+    assert_eq!(result.tokens.len(), 1);
+    let log = String::from_utf8(tracer.out).unwrap();
+    assert_eq!(
+        log.lines().collect::<Vec<_>>(),
+        vec![
+            "enter group `ab . done;` at 1:3",
+            "  try `'a' & 'b' : ab;` at 1:14 on token 0",
+            "  match `'a' & 'b' : ab;` at 1:14 on tokens 0..2 (text 0..2)",
+            "  rewrite `'a' & 'b' : ab;` at 1:14 on tokens 0..2, leaving 1 in their place",
+            "  try `ab . done;` at 1:3 on token 0",
+            "  match `ab . done;` at 1:3 on tokens 0..1 (text 0..2)",
+            "  rewrite `ab . done;` at 1:3 on tokens 0..1, leaving 1 in their place",
+            "leave group `ab . done;` at 1:3 after 2 rewrites",
+        ]
+    );
+}
+
+#[test]
+pub fn stepper_test() {
+    let prog =
+        compile_program("{ ab . done; 'a' & 'b' : ab; }", &TransformRegistry::new()).unwrap();
+    let mut stepper = prog.stepper("xab", &ExecutionLimits::default());
+    let step = stepper.next().unwrap().unwrap();
+    assert_eq!(step.rule.text, "'a' & 'b' : ab;");
+    assert_eq!(
+        (step.start, step.old_len, step.new_len, step.range.clone()),
+        (1, 2, 1, 1..3)
+    );
+    assert_eq!(stepper.tokens().len(), 2);
+    assert!(step.creates(stepper.tokens(), "ab"));
+
+    let step = stepper.next().unwrap().unwrap();
+    assert_eq!(step.rule.text, "ab . done;");
+    assert_eq!(stepper.tokens()[1].data, vec!["done"]);
+    assert!(stepper.next().is_none());
+
+    let result = stepper.finish().unwrap();
+    assert_eq!(result.tokens.len(), 2);
+}
+
+#[test]
+pub fn breakpoint_test() {
+    let prog = compile_program(
+        "{ 'a' & 'b' : ab; ab & ab : pair; }",
+        &TransformRegistry::new(),
+    )
+    .unwrap();
+    let mut stepper = prog.stepper("ababab", &ExecutionLimits::default());
+    let step = stepper
+        .run_until(|step, tokens| step.creates(tokens, "pair"))
+        .unwrap()
+        .unwrap();
+    assert_eq!((step.start, step.range), (0, 0..4));
+    assert_eq!(
+        stepper
+            .tokens()
+            .iter()
+            .map(|t| t.data[0].as_str())
+            .collect::<Vec<_>>(),
+        vec!["pair", "ab"]
+    );
+    assert!(stepper
+        .run_until(|step, tokens| step.creates(tokens, "pair"))
+        .is_none());
+    assert_eq!(stepper.finish().unwrap().tokens.len(), 2);
+}
+
+#[test]
+pub fn stepper_error_test() {
+    let limits = ExecutionLimits {
+        max_rewrites: Some(1),
+        ..Default::default()
+    };
+    let prog = compile_program("{ 'a' ~; }", &TransformRegistry::new()).unwrap();
+    let mut stepper = prog.stepper("aa", &limits);
+    assert!(stepper.next().unwrap().is_ok());
+    assert!(stepper.next().unwrap().is_err());
+    assert!(stepper.next().is_none());
+    assert_eq!(
+        stepper.finish().unwrap_err().to_string(),
+        "ran out of rewrites in rule `'a' ~;` at 1:3"
+    );
+}
+
+#[test]
+pub fn execute_test() {
+    let prog = compile_program("{ 'a' & 'b' : ab; }\n%'c' ~;", &TransformRegistry::new()).unwrap();
+    let mut tokens = char_tokens("abcab");
+    let mut budget = Budget::new(&ExecutionLimits::default());
+    assert!(prog.reps[0].execute(&mut tokens, &mut budget).unwrap());
+    assert!(prog.reps[1].execute(&mut tokens, &mut budget).unwrap());
+    assert!(!prog.reps[1].execute(&mut tokens, &mut budget).unwrap());
+    let tags = tokens.iter().map(|t| t.data.join(" ")).collect::<Vec<_>>();
+    assert_eq!(tags, vec!["ab", "ab"]);
+}
+
+const ASSIGN_PROG: &str = "
+%{ a..z . letter; letter+ . word; ws~; }
+{ word & '=' & word & ';' : assign [0, 2]; }
+";
+
+#[test_case("a = b;\nc = d;", 12..13, "e"; "changing a word")]
+#[test_case("a = b;\nc = d;", 5..6, ""; "removing a dropped token")]
+#[test_case("a = b;\nc = d;", 7..7, "x = y; "; "inserting a statement")]
+#[test_case("a = b;\nc = d;", 2..3, " "; "breaking a statement")]
+#[test_case("a = b;\nc = d", 12..12, " = e"; "continuing a broken statement")]
+pub fn reparse_test(text: &str, range: Range<usize>, replacement: &str) {
+    let prog = compile_program(ASSIGN_PROG, &TransformRegistry::new()).unwrap();
+    let edit = TextEdit::new(range, replacement);
+    let new_text = edit.apply(text);
+    let reparsed = prog.reparse(prog.eval(text), &edit, &new_text);
+    let expected = prog.eval(&new_text);
+    assert_eq!(format!("{reparsed:?}"), format!("{expected:?}"));
+}
+
+// Passes and groups on top of each other, where an edit changes how the
+// text on either side of it is paired up.
+const LAYERED_PROG: &str = "
+%{ ws~; }
+{ 'a' & 'b' : ab; ab & 'a' : aba; }
+%{ 'c' . c; 'c' & 'c' . cc; }
+%'b' => insert(';'), semi;
+<%'c' & ab . cab;
+";
+
+#[test_case(" cccccccc", 2..4, "a"; "pairing again after the edit")]
+#[test_case("abab cc", 2..2, "a"; "joining groups")]
+#[test_case("ccc", 3..3, "c"; "appending")]
+#[test_case("cab", 0..1, ""; "removing a reversed match")]
+pub fn reparse_layered_test(text: &str, range: Range<usize>, replacement: &str) {
+    let prog = compile_program(LAYERED_PROG, &TransformRegistry::new()).unwrap();
+    let edit = TextEdit::new(range, replacement);
+    let new_text = edit.apply(text);
+    let reparsed = prog.reparse(prog.eval(text), &edit, &new_text);
+    let expected = prog.eval(&new_text);
+    assert_eq!(format!("{reparsed:?}"), format!("{expected:?}"));
+}
+
+#[test]
+pub fn reparse_random_edits_test() {
+    let prog = compile_program(LAYERED_PROG, &TransformRegistry::new()).unwrap();
+    let chars = [' ', 'a', 'b', 'c'];
+    let mut seed = 11u64;
+    let mut next = |n: usize| {
+        seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+        (seed >> 33) as usize % n
+    };
+    let mut texts = vec!["ab cc abca cccc bab".to_string()];
+    let mut edits = vec![];
+    for _ in 0..300 {
+        let text = texts.last().unwrap();
+        let start = next(text.len() + 1);
+        let end = start + next((text.len() - start).min(4) + 1);
+        let replacement = (0..next(5)).map(|_| chars[next(4)]).collect::<String>();
+        let edit = TextEdit::new(start..end, &replacement);
+        texts.push(edit.apply(text));
+        edits.push(edit);
+    }
+    let mut result = prog.eval(&texts[0]);
+    for (edit, text) in edits.iter().zip(&texts[1..]) {
+        result = prog.reparse(result, edit, text);
+        let expected = prog.eval(text);
+        assert_eq!(format!("{result:?}"), format!("{expected:?}"), "{edit:?}");
+    }
+}
+
+#[test]
+pub fn reparse_wrong_edit_test() {
+    let prog = compile_program(ASSIGN_PROG, &TransformRegistry::new()).unwrap();
+    let edit = TextEdit::new(0..1, "x");
+    let reparsed = prog.reparse(prog.eval("a = b;"), &edit, "c = d;");
+    assert_eq!(
+        format!("{reparsed:?}"),
+        format!("{:?}", prog.eval("c = d;"))
+    );
+}
+
+fn nested(token: &Token<Vec<String>>) -> String {
+    match token.children() {
+        Some(children) => format!(
+            "({})",
+            children.iter().map(nested).collect::<Vec<_>>().join(" ")
+        ),
+        None => token.content().to_owned(),
+    }
+}
+
+#[test_case("%a..z . expr;\n{ <expr & '^' & expr : pow, expr; }", "a^b^c", "(a ^ (b ^ c))"; "right-associative rule")]
+#[test_case("%a..z . expr;\n{ expr & '^' & expr : pow, expr; }", "a^b^c", "((a ^ b) ^ c)"; "left-associative rule")]
+#[test_case("%a..z . expr;\n{ <expr & '^' & expr : pow, expr; expr & '*' & expr : mul, expr; }", "a*b^c^d*e", "((a * (b ^ (c ^ d))) * e)"; "mixed directions in a group")]
+#[test_case("%a..z . expr;\n<{ expr & '-' & expr : sub, expr; expr & '=' & expr : set, expr; }", "a=b=c-d-e", "(a = (b = (c - (d - e))))"; "reversed group")]
+#[test_case("%a..z . expr;\n%<expr & ',' & expr : pair, expr;", "a,b,c", "a , (b , c)"; "reversed pass")]
+#[test_case("%a..z . expr;\n<%expr & ',' & expr : pair, expr;", "a,b,c", "a , (b , c)"; "reversed pass the other way around")]
+#[test_case("%a..z . expr;\n%expr & ',' & expr : pair, expr;", "a,b,c", "(a , b) , c"; "forward pass")]
+#[test_case("%<{ a..z . expr; expr & ',' & expr : pair, expr; }", "a,b,c", "a , (b , c)"; "reversed group pass")]
+pub fn reverse_test(prog: &str, text: &str, expected: &str) {
+    let tox = eval_prog_from_text(prog, text);
+    let shape = tox.iter().map(nested).collect::<Vec<_>>().join(" ");
+    assert_eq!(shape, expected);
+}
+
+#[test_case("{ 'a' & b . c; 'x' . b; }", "ax"; "a rewrite after a failed start")]
+#[test_case("<{ b & 'a' . c; 'x' . b; }", "xa"; "a rewrite before a failed start in a reversed group")]
+#[test_case("%b..z . expr;\n{ 'a' & oper . c; precedence expr { left '+'; } }", "ax+y"; "a rewrite made by a precedence declaration")]
+#[test_case("{ 'a' & b & 'a' . c; 'x'~; 'y' . b; }", "axya"; "a rewrite after a removal")]
+pub fn group_retry_test(prog: &str, text: &str) {
+    let tox = eval_prog_from_text(prog, text);
+    assert_eq!(tags_of(&tox), vec![vec!["c"]]);
+}
+
+const PREC_PROG: &str = "%{ a..z . expr; ws~; }\n{ precedence expr { right '^'; prefix '-'; left '*' '/'; left '+' '-'; } }";
+
+#[test_case("a+b*c", "(a + (b * c))"; "tighter level first")]
+#[test_case("a-b-c", "((a - b) - c)"; "left-associative level")]
+#[test_case("a^b^c", "(a ^ (b ^ c))"; "right-associative level")]
+#[test_case("-a^b*c", "((- (a ^ b)) * c)"; "prefix level")]
+#[test_case("a - -b", "(a - (- b))"; "prefix after a binary operator")]
+#[test_case("a +", "a +"; "missing operand")]
+pub fn precedence_test(text: &str, expected: &str) {
+    let tox = eval_prog_from_text(PREC_PROG, text);
+    let shape = tox.iter().map(nested).collect::<Vec<_>>().join(" ");
+    assert_eq!(shape, expected);
+}
+
+#[test]
+pub fn precedence_tags_test() {
+    let tox = eval_prog_from_text(PREC_PROG, "a*b+c");
+    assert_eq!(tox[0].data, vec!["expr", "oper", "binary", "+", "level4"]);
+    assert_eq!(
+        tox[0].children().unwrap()[0].data,
+        vec!["expr", "oper", "binary", "*", "level3"]
+    );
+}
+
+#[test_case("%a..z . expr;\n%precedence expr { left '*'; left '+'; }", "a+b*c,d*e", "(a + (b * c)) , (d * e)"; "pass over several runs")]
+#[test_case("%a..z . expr;\n%<precedence expr { left '*'; left '+'; }", "a+b*c,d*e", "(a + (b * c)) , (d * e)"; "reversed pass over several runs")]
+#[test_case("%a..z . expr;\n%precedence expr { left '+'; }\n'(' & expr & ')' : parens, expr;", "(a+b)", "(( (a + b) ))"; "pass inside parens")]
+pub fn precedence_pass_test(prog: &str, text: &str, expected: &str) {
+    let tox = eval_prog_from_text(prog, text);
+    let shape = tox.iter().map(nested).collect::<Vec<_>>().join(" ");
+    assert_eq!(shape, expected);
+}
+
+const LINES_PROG: &str = "
+boundary u10;
+%{ a..z . letter; letter+ . word; u32~; }
+{ word & '=' & word & u10 : assign; }
+";
+
+#[test_case("ab = cd\n", 4; "one line")]
+#[test_case("ab = cd\nef = gh\nij = kl\n", 2; "a few lines")]
+#[test_case("ab = cd\nef = gh\nij = kl", 8; "more threads than lines")]
+#[test_case("ab = cd\nef =\nij = kl\n", 3; "a broken line")]
+pub fn parallel_test(text: &str, threads: usize) {
+    let prog = compile_program(LINES_PROG, &TransformRegistry::new()).unwrap();
+    let text = text.repeat(20);
+    let expected = prog.eval(&text);
+    assert_eq!(
+        format!("{:?}", prog.eval_parallel(&text, threads)),
+        format!("{expected:?}")
+    );
+}
+
+#[test]
+pub fn split_at_boundaries_test() {
+    let tokens = char_tokens("a\nb\nc\nd");
+    let chunks = split_at_boundaries(tokens, &has_tag_owned("u10".to_owned()), 2);
+    assert_eq!(chunks.iter().map(Vec::len).collect::<Vec<_>>(), vec![4, 3]);
+}
+
+fn windows(prog: &SeqProg, chunks: &[&str]) -> Vec<(usize, String)> {
+    let mut windows = vec![];
+    prog.eval_chunks(chunks, |offset, result| {
+        let tags = result.tokens.iter().map(|t| t.data[0].as_str());
+        windows.push((offset, tags.collect::<Vec<_>>().join(" ")));
+    })
+    .unwrap();
+    windows
+}
+
+#[test_case(&["ab = cd\nef = gh\n"], vec![(0, "assign"), (8, "assign")]; "one chunk")]
+#[test_case(&["ab =", " cd", "\nef = g", "h\n"], vec![(0, "assign"), (8, "assign")]; "chunks inside lines")]
+#[test_case(&["ab = cd\nef =\n", "ij = kl"], vec![(0, "assign"), (8, "word = \n"), (13, "word = word")]; "unfinished lines")]
+#[test_case(&[], vec![]; "no text")]
+#[test_case(&["a", "b", " ", "=", " ", "c", "d", "\n", "é", "f"], vec![(0, "assign"), (8, "é word")]; "a character at a time")]
+#[test_case(&["é = cd\nef", " = gh\n"], vec![(0, "é = word \n"), (8, "assign")]; "non-ASCII text")]
+pub fn stream_test(chunks: &[&str], expected: Vec<(usize, &str)>) {
+    let prog = compile_program(LINES_PROG, &TransformRegistry::new()).unwrap();
+    let expected = expected
+        .into_iter()
+        .map(|(offset, tags)| (offset, tags.to_owned()))
+        .collect::<Vec<_>>();
+    assert_eq!(windows(&prog, chunks), expected);
+}
+
+#[test_case(&["a\n", "\nb\n", "\nc"], vec![(0, "a \n \n"), (3, "b \n \n"), (6, "c")]; "chunks between the newlines")]
+#[test_case(&["a\n\n\nb"], vec![(0, "a \n \n"), (3, "\n b")]; "three newlines")]
+pub fn stream_boundary_test(chunks: &[&str], expected: Vec<(usize, &str)>) {
+    let prog = compile_program("boundary u10 & u10;", &TransformRegistry::new()).unwrap();
+    let expected = expected
+        .into_iter()
+        .map(|(offset, tags)| (offset, tags.to_owned()))
+        .collect::<Vec<_>>();
+    assert_eq!(windows(&prog, chunks), expected);
+}
+
+#[test]
+pub fn non_ascii_test() {
+    let tox = eval_prog_from_text("%a..z+ . word;", "hé ça");
+    let tox = tox
+        .iter()
+        .map(|t| (t.content(), t.content_range()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        tox,
+        vec![
+            ("h", 0..1),
+            ("é", 1..3),
+            (" ", 3..4),
+            ("ç", 4..6),
+            ("a", 6..7)
+        ]
+    );
+}
+
+#[test]
+pub fn stream_without_boundary_test() {
+    let prog = compile_program("%a..z . letter;", &TransformRegistry::new()).unwrap();
+    assert!(matches!(StreamParser::new(&prog), Err(NoBoundary)));
+    let error = prog.eval_reader(Trickle(b"ab", 1), |_, _| {}).unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+}
+
+/// Gives the bytes it's made with a few at a time.
+struct Trickle<'a>(&'a [u8], usize);
+
+impl Read for Trickle<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.1.min(self.0.len()).min(buf.len());
+        buf[..len].copy_from_slice(&self.0[..len]);
+        self.0 = &self.0[len..];
+        Ok(len)
+    }
+}
+
+#[test]
+pub fn stream_reader_test() {
+    let prog = compile_program(LINES_PROG, &TransformRegistry::new()).unwrap();
+    let mut offsets = vec![];
+    prog.eval_reader(Trickle(b"ab = cd\nef = gh\nij = kl\n", 3), |offset, _| {
+        offsets.push(offset)
+    })
+    .unwrap();
+    assert_eq!(offsets, vec![0, 8, 16]);
+
+    let error = prog
+        .eval_reader(Trickle(b"ab = \xc3", 3), |_, _| {})
+        .unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+}
+
+#[test_case("def hexdigit = 0..9 | a..f | A..F;\n$hexdigit & $hexdigit . byte;", "f0", "byte"; "a definition")]
+#[test_case("def number = $int | $decimal;\ndef int = 0..9+ & !'.';\ndef decimal = 0..9+ & '.' & 0..9+;\n%$number . num;\n{ num & '+' & num : sum; }", "1.5+2", "sum"; "definitions used before they're declared")]
+#[test_case("def ab = 'a' & 'b';\n%$ab . pair;\n%$ab ~;", "abab", "pair pair"; "a definition used twice")]
+#[test_case("def letter = a..z;\n%$letter . word;", "xy", "word word"; "no tags from definitions")]
+pub fn def_test(prog: &str, text: &str, expected: &str) {
+    let tox = eval_prog_from_text(prog, text);
+    let tags = tox.iter().map(|t| t.data.join(" ")).collect::<Vec<_>>();
+    assert_eq!(tags.join(" "), expected);
+}
+
+#[test_case("%$digit . num;", "undefined name `$digit` at 1:2"; "undefined name")]
+#[test_case("def a = 'a';\ndef a = 'b';", "`a` is defined more than once at 2:1"; "defined twice")]
+#[test_case("def a = 'a' & $a?;", "`a` is defined in terms of itself at 1:9"; "defined in terms of itself")]
+#[test_case("def a = $b;\ndef b = 'b' | $c;\ndef c = $a;", "`a` is defined in terms of itself through `b`, `c` at 1:9"; "defined in terms of itself through others")]
+pub fn def_error_test(prog: &str, expected: &str) {
+    let err = compile_program(prog, &TransformRegistry::new()).unwrap_err();
+    assert_eq!(err.to_string(), expected);
+}
+
+#[test]
+pub fn definition_test() {
+    let prog = compile_program("def digit = 0..9;", &TransformRegistry::new()).unwrap();
+    let digits = RepeatedSeq::of(Box::new(prog.definition("digit").unwrap()));
+    let tokens = char_tokens("12a");
+    let tokens = tokens.iter().collect::<Vec<_>>();
+    assert_eq!(digits.match_tokens(&tokens), Some(2));
+    assert_eq!(
+        prog.definition("letter").err(),
+        Some(CompileErrorKind::UndefinedName("letter".to_owned()))
+    );
+}
+
+fn grammars() -> MemoryResolver {
+    let mut resolver = MemoryResolver::new();
+    resolver
+        .insert(
+            "lex.tuck",
+            "def digit = 0..9;\ndef number = $digit+;\ndef letter = a..z;",
+        )
+        .insert("words.tuck", "%a..z+ . word;\n%u32~;")
+        .insert("bad.tuck", "def a = 'a';\n%$b . b;")
+        .insert("loop.tuck", "import \"loop2.tuck\";")
+        .insert("loop2.tuck", "import \"loop.tuck\";")
+        .insert("marks.tuck", "def digit = 0..9;\n%$digit => insert, mark;")
+        .insert("left.tuck", "import \"marks.tuck\";\ndef left = $digit;")
+        .insert("right.tuck", "import \"marks.tuck\";\ndef right = $digit;")
+        .insert("other.tuck", "def digit = 0..7;");
+    resolver
+}
+
+#[test_case("import \"lex.tuck\";\n%$number . num;", "12", "num"; "a whole grammar")]
+#[test_case("import number, letter from \"lex.tuck\";\n%$number . num;\n%$letter . let;", "1a", "num let"; "a selection")]
+#[test_case("import \"words.tuck\";\n{ word & word : pair; }", "ab cd", "pair"; "rules from a grammar")]
+#[test_case("import number from \"lex.tuck\";\ndef int = $number;\n%$int . int;", "7", "int"; "a definition in terms of an import")]
+#[test_case("import \"left.tuck\";\nimport \"right.tuck\";\n%$left . l;", "12", "l mark l mark"; "a grammar two others import")]
+#[test_case("import digit from \"marks.tuck\";\nimport \"marks.tuck\";", "1", "1 u49 mark"; "a selection before the whole grammar")]
+pub fn import_test(prog: &str, text: &str, expected: &str) {
+    let prog = compile_program_with(prog, &TransformRegistry::new(), &grammars()).unwrap();
+    let tags = prog
+        .eval(text)
+        .tokens
+        .iter()
+        .map(|t| t.data.join(" "))
+        .collect::<Vec<_>>();
+    assert_eq!(tags.join(" "), expected);
+}
+
+#[test_case("import \"nope.tuck\";", "couldn't import `nope.tuck`: no such grammar at 1:1"; "a missing grammar")]
+#[test_case("import \"bad.tuck\";", "undefined name `$b` at bad.tuck:2:2"; "an error in a grammar")]
+#[test_case("import \"loop.tuck\";", "`loop.tuck` ends up importing itself at loop2.tuck:1:1"; "a cycle")]
+#[test_case("import digit, word from \"lex.tuck\";", "`lex.tuck` doesn't define `word` at 1:15"; "a name a grammar doesn't define")]
+#[test_case("import \"lex.tuck\";\ndef digit = 0..7;", "`digit` is defined more than once at 2:1"; "a definition that clashes with an import")]
+#[test_case("import \"lex.tuck\";\nimport \"other.tuck\";", "`digit` is defined more than once at 2:1"; "two grammars that define the same name")]
+#[test_case("import \"lex.tuck\";\nimport digit from \"other.tuck\";", "`digit` is defined more than once at 2:8"; "a selected name that clashes with an import")]
+pub fn import_error_test(prog: &str, expected: &str) {
+    let err = compile_program_with(prog, &TransformRegistry::new(), &grammars()).unwrap_err();
+    assert_eq!(err.to_string(), expected);
+}
+
+#[test]
+pub fn file_resolver_test() {
+    let root = std::env::temp_dir().join(format!("tuck-import-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(root.join("lex.tuck"), "def number = 0..9+;").unwrap();
+
+    let resolver = FileResolver::new(&root);
+    let prog = "import \"lex.tuck\";\n%$number . num;";
+    let result = compile_program_with(prog, &TransformRegistry::new(), &resolver);
+    let err = compile_program_with(
+        "import \"nope.tuck\";",
+        &TransformRegistry::new(),
+        &resolver,
+    )
+    .unwrap_err();
+    std::fs::remove_dir_all(&root).unwrap();
+
+    let tokens = result.unwrap().eval("42").tokens;
+    assert_eq!(tokens.len(), 1);
+    assert_eq!(tokens[0].data, vec!["num"]);
+    assert!(matches!(err.kind, CompileErrorKind::ImportFailed(..)));
+}
+
+const MODES_PROG: &str = "
+%{
+    in main, code {
+        '\"' . quote, push(string);
+        a..z+ . word;
+        0..9+ . int;
+        '+' . plus;
+        ws~;
+    }
+    in code { '}' . close, pop(code); }
+    in string {
+        '\"' . unquote, pop(string);
+        '$' & '{' . open, push(code);
+        (a..z | ws)+ . text;
+    }
+}
+";
+
+#[test_case("\"a ${b + 1} c\"", "quote text open word plus int close text unquote"; "interpolation")]
+#[test_case("x \"${\"y\"}\" z", "word quote open quote text unquote close unquote word"; "a string in an interpolation")]
+#[test_case("\"a b\" + 1", "quote text unquote plus int"; "no interpolation")]
+#[test_case("a } \"b\"", "word } u125 quote text unquote"; "a pop that isn't active")]
+pub fn modes_test(text: &str, expected: &str) {
+    let tox = eval_prog_from_text(MODES_PROG, text);
+    let tags = tox.iter().map(|t| t.data.join(" ")).collect::<Vec<_>>();
+    assert_eq!(tags.join(" "), expected);
+}
+
+#[test]
+pub fn modes_tree_test() {
+    let prog = format!(
+        "{MODES_PROG}{{ open & (word | int | plus)+ & close : interp; }}
+        {{ quote & (text | interp)* & unquote : string; }}"
+    );
+    let tox = eval_prog_from_text(&prog, "\"a ${b + 1} c\"");
+    assert_eq!(tox.len(), 1);
+    assert_eq!(tox[0].data, vec!["string"]);
+    let parts = tox[0].children().unwrap();
+    let tags = parts.iter().map(|t| t.data.join(" ")).collect::<Vec<_>>();
+    assert_eq!(tags, vec!["quote", "text", "interp", "text", "unquote"]);
+}
+
+#[test_case("in string { 'a' . a; }", "modes can only be used in a `%{ }` pass at 1:1"; "an in group outside a pass")]
+#[test_case("{ 'a' . a, push(x); }", "modes can only be used in a `%{ }` pass at 1:3"; "a switch outside a pass")]
+#[test_case("%{ 'a' . a, push(x); precedence a { left '+'; } }", "a `%{ }` pass with modes can only have rules and `in` groups at 1:22"; "a precedence declaration in a lexer")]
+pub fn modes_error_test(prog: &str, expected: &str) {
+    let err = compile_program(prog, &TransformRegistry::new()).unwrap_err();
+    assert_eq!(err.to_string(), expected);
+}
+
+#[test_case("%('a'?)* . x;", "b", vec![("", "x"), ("b", "b u98")]; "an empty match")]
+#[test_case("%('a'?)* . x;", "ab", vec![("a", "x"), ("", "x"), ("b", "b u98")]; "a match then an empty one")]
+#[test_case("{ ('a'?)* & 'b' . x; }", "aab", vec![("aab", "x")]; "in a group")]
+#[test_case("('a'? : y)* & 'c' . x;", "ab", vec![("a", "a u97"), ("b", "b u98")]; "no match")]
+pub fn nullable_repeat_test(prog: &str, text: &str, expected: Vec<(&str, &str)>) {
+    let tox = eval_prog_from_text(prog, text);
+    let tox = tox
+        .iter()
+        .map(|t| (t.content(), t.data.join(" ")))
+        .collect::<Vec<_>>();
+    let expected = expected
+        .into_iter()
+        .map(|(c, d)| (c, d.to_owned()))
+        .collect::<Vec<_>>();
+    assert_eq!(tox, expected);
+}
@@ -1,3 +1,4 @@
+use std::fmt::{Display, Formatter};
 use std::marker::PhantomData;
 
 use crate::*;
@@ -10,6 +11,354 @@ mod tests;
 
 pub trait Sequence<T> {
     fn match_tokens(&self, tokens: &[&Token<T>]) -> Option<usize>;
+
+    /// What the first token of a match could look like, if known.
+    fn expected(&self) -> Vec<Expected> {
+        vec![]
+    }
+
+    /// Like `match_tokens`, but on failure reports how far the sequence got
+    /// and what it was looking for there.
+    fn diagnose(&self, tokens: &[&Token<T>]) -> Result<usize, Failure> {
+        self.match_tokens(tokens)
+            .ok_or_else(|| Failure::new(0, self.expected()))
+    }
+
+    /// Like `match_tokens`, but also records the captures made inside the
+    /// match. Capture ranges are shifted by `offset`.
+    fn capture_tokens(
+        &self,
+        tokens: &[&Token<T>],
+        _offset: usize,
+        _captures: &mut Vec<Capture>,
+    ) -> Option<usize> {
+        self.match_tokens(tokens)
+    }
+
+    /// What the first token of a match can be. Used to skip start positions
+    /// where the sequence can't match, so it must not leave anything out.
+    fn first_set(&self) -> FirstSet {
+        FirstSet::Any
+    }
+
+    /// Like `match_tokens`, but also reports how many tokens were looked at
+    /// to decide, with running off the end counting as looking at one more.
+    /// Whatever happens to the tokens past that point can't change the
+    /// result. The default assumes that every token was looked at.
+    fn reach(&self, tokens: &[&Token<T>]) -> (Option<usize>, usize) {
+        (self.match_tokens(tokens), tokens.len() + 1)
+    }
+
+    /// Matches `tokens`, reporting where each element of the sequence and
+    /// each capture landed.
+    fn match_elements(&self, tokens: &[&Token<T>]) -> Option<Match> {
+        let mut captures = vec![];
+        let len = self.capture_tokens(tokens, 0, &mut captures)?;
+        Some(Match {
+            captures,
+            ..Match::whole(len)
+        })
+    }
+}
+
+impl<T, X: Sequence<T> + ?Sized> Sequence<T> for std::sync::Arc<X> {
+    fn match_tokens(&self, tokens: &[&Token<T>]) -> Option<usize> {
+        self.as_ref().match_tokens(tokens)
+    }
+
+    fn expected(&self) -> Vec<Expected> {
+        self.as_ref().expected()
+    }
+
+    fn diagnose(&self, tokens: &[&Token<T>]) -> Result<usize, Failure> {
+        self.as_ref().diagnose(tokens)
+    }
+
+    fn capture_tokens(
+        &self,
+        tokens: &[&Token<T>],
+        offset: usize,
+        captures: &mut Vec<Capture>,
+    ) -> Option<usize> {
+        self.as_ref().capture_tokens(tokens, offset, captures)
+    }
+
+    fn first_set(&self) -> FirstSet {
+        self.as_ref().first_set()
+    }
+
+    fn reach(&self, tokens: &[&Token<T>]) -> (Option<usize>, usize) {
+        self.as_ref().reach(tokens)
+    }
+
+    fn match_elements(&self, tokens: &[&Token<T>]) -> Option<Match> {
+        self.as_ref().match_elements(tokens)
+    }
+}
+
+/// The layout of a successful match. All ranges are relative to the first
+/// matched token.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Match {
+    pub len: usize,
+    /// One range per element of the sequence (e.g. per operand of `&`).
+    pub elements: Vec<Range<usize>>,
+    pub captures: Vec<Capture>,
+}
+
+impl Match {
+    /// A match of `len` tokens with a single element and no captures.
+    pub fn whole(len: usize) -> Match {
+        Match {
+            len,
+            elements: std::iter::once(0..len).collect(),
+            captures: vec![],
+        }
+    }
+}
+
+impl Match {
+    /// The ranges of tokens picked out by `selector`, in order.
+    pub fn select(&self, selector: &Selector) -> Vec<Range<usize>> {
+        match selector {
+            Selector::Element(i) => self.elements.get(*i).cloned().into_iter().collect(),
+            Selector::Capture(name) => self
+                .captures
+                .iter()
+                .filter(|c| c.name.as_ref() == Some(name))
+                .map(|c| c.range.clone())
+                .collect(),
+        }
+    }
+
+    /// The indices of the marked tokens, or of every token if nothing was
+    /// marked.
+    pub fn marked(&self) -> Vec<usize> {
+        if self.captures.is_empty() {
+            (0..self.len).collect()
+        } else {
+            self.captures.iter().flat_map(|c| c.range.clone()).collect()
+        }
+    }
+}
+
+/// Picks part of a `Match`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Selector {
+    /// The nth element of the sequence, counting from 0.
+    Element(usize),
+    /// Every capture with this name.
+    Capture(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capture {
+    /// `None` for anonymous marks.
+    pub name: Option<String>,
+    pub range: Range<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expected {
+    Literal(String),
+    Tag(String),
+    Range(char, char),
+}
+
+impl Display for Expected {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Expected::Literal(s) => write!(f, "'{s}'"),
+            Expected::Tag(s) => write!(f, "{s}"),
+            Expected::Range(s, e) => write!(f, "{s}..{e}"),
+        }
+    }
+}
+
+impl Expected {
+    /// Whether a token with this content, and the tags that `has_tag`
+    /// accepts, fits the description.
+    pub fn describes(&self, content: &str, has_tag: impl Fn(&str) -> bool) -> bool {
+        match self {
+            Expected::Literal(s) => content == s,
+            Expected::Tag(t) => has_tag(t),
+            Expected::Range(s, e) => {
+                let mut chars = content.chars();
+                matches!((chars.next(), chars.next()), (Some(c), None) if (*s..=*e).contains(&c))
+            }
+        }
+    }
+}
+
+/// Text longer than this gets a bit of its own in a signature instead of
+/// one for its content, since literals that long are rare.
+const SHORT_TEXT: usize = 16;
+
+/// A bit for a token's tag or text, picked by hashing it.
+fn bit(kind: u8, text: &str) -> u64 {
+    let hash = text
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325 ^ kind as u64, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+        });
+    // FNV barely changes the top bits when only the last byte differs.
+    let hash = (hash ^ hash >> 33).wrapping_mul(0xff51_afd7_ed55_8ccd);
+    1 << ((hash ^ hash >> 33) >> 58)
+}
+
+fn text_bit(text: &str) -> u64 {
+    match text.len() > SHORT_TEXT {
+        true => bit(0, ""),
+        false => bit(1, text),
+    }
+}
+
+/// Sums up a token as one bit for its text and one for each of its tags.
+/// Every token that a first set admits shares a bit with the set's `mask`.
+pub fn signature<S: AsRef<str>>(content: &str, tags: impl IntoIterator<Item = S>) -> u64 {
+    tags.into_iter()
+        .fold(text_bit(content), |bits, tag| bits | bit(2, tag.as_ref()))
+}
+
+impl Expected {
+    /// The bits of `signature` that a token this describes must have one of.
+    pub fn mask(&self) -> u64 {
+        match self {
+            Expected::Literal(s) => text_bit(s),
+            Expected::Tag(t) => bit(2, t),
+            Expected::Range(s, e) if (*s as u32) + 64 > *e as u32 => {
+                (*s..=*e).fold(0, |bits, c| bits | text_bit(c.encode_utf8(&mut [0; 4])))
+            }
+            Expected::Range(..) => u64::MAX,
+        }
+    }
+}
+
+/// What the first token of a sequence's match can be.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FirstSet {
+    /// Nothing is known about it.
+    Any,
+    /// It fits one of `expected`. If `nullable`, the sequence might also
+    /// match without taking any tokens, in which case anything can follow.
+    Of {
+        expected: Vec<Expected>,
+        nullable: bool,
+    },
+}
+
+impl FirstSet {
+    pub fn of(expected: Vec<Expected>) -> FirstSet {
+        FirstSet::Of {
+            expected,
+            nullable: false,
+        }
+    }
+
+    /// The first set of a sequence that may match no tokens at all.
+    pub fn nothing() -> FirstSet {
+        FirstSet::Of {
+            expected: vec![],
+            nullable: true,
+        }
+    }
+
+    /// The first set of a choice between two sequences.
+    pub fn or(self, other: FirstSet) -> FirstSet {
+        match (self, other) {
+            (
+                FirstSet::Of { expected, nullable },
+                FirstSet::Of {
+                    expected: more,
+                    nullable: more_nullable,
+                },
+            ) => FirstSet::Of {
+                expected: expected.into_iter().chain(more).collect(),
+                nullable: nullable || more_nullable,
+            },
+            _ => FirstSet::Any,
+        }
+    }
+
+    /// The first set of this sequence followed by another.
+    pub fn then(self, next: FirstSet) -> FirstSet {
+        match self {
+            FirstSet::Of {
+                expected,
+                nullable: true,
+            } => FirstSet::of(expected).or(next),
+            other => other,
+        }
+    }
+
+    /// The first set of a sequence that may also match nothing.
+    pub fn optional(self) -> FirstSet {
+        self.or(FirstSet::nothing())
+    }
+
+    /// The bits of `signature` that the first token of a match has one of,
+    /// or all of them if any token could start one.
+    pub fn mask(&self) -> u64 {
+        match self {
+            FirstSet::Of {
+                expected,
+                nullable: false,
+            } => expected.iter().fold(0, |bits, e| bits | e.mask()),
+            _ => u64::MAX,
+        }
+    }
+
+    /// Whether a match could start with a token with this content and the
+    /// tags that `has_tag` accepts.
+    pub fn admits(&self, content: &str, has_tag: impl Fn(&str) -> bool) -> bool {
+        match self {
+            FirstSet::Any => true,
+            FirstSet::Of { expected, nullable } => {
+                *nullable || expected.iter().any(|e| e.describes(content, &has_tag))
+            }
+        }
+    }
+}
+
+/// The furthest point a sequence reached before it stopped matching.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Failure {
+    /// Index of the offending token, relative to the start of the attempt.
+    pub position: usize,
+    /// What would have been accepted at `position`.
+    pub expected: Vec<Expected>,
+    /// What matched right before `position`, if anything did.
+    pub after: Vec<Expected>,
+}
+
+impl Failure {
+    pub fn new(position: usize, expected: Vec<Expected>) -> Failure {
+        Failure {
+            position,
+            expected,
+            after: vec![],
+        }
+    }
+
+    /// Keeps whichever failure got further, merging the two if they tie.
+    pub fn furthest(self, other: Failure) -> Failure {
+        if other.position > self.position {
+            other
+        } else if other.position < self.position {
+            self
+        } else {
+            let mut merged = self;
+            for e in other.expected {
+                if !merged.expected.contains(&e) {
+                    merged.expected.push(e);
+                }
+            }
+            if merged.after.is_empty() {
+                merged.after = other.after;
+            }
+            merged
+        }
+    }
 }
 
 pub fn match_all_tokens<T>(seq: &impl Sequence<T>, tokens: &[&Token<T>]) -> bool {
@@ -22,10 +371,14 @@ pub fn test_and_transform<T>(
     tokens: &mut Vec<Token<'_, T>>,
     start_index: usize,
 ) -> Option<usize> {
-    let test_result = seq.match_tokens(&tokens[start_index..].iter().collect::<Vec<_>>());
+    let refs = tokens[start_index..].iter().collect::<Vec<_>>();
+    let test_result = seq.match_tokens(&refs);
     if let Some(len) = test_result {
+        let m = seq
+            .match_elements(&refs)
+            .unwrap_or_else(|| Match::whole(len));
         let end_index = start_index + len;
-        let new_tox = transform.transform(tokens.drain(start_index..end_index).collect());
+        let new_tox = transform.transform_match(tokens.drain(start_index..end_index).collect(), &m);
         let new_len = new_tox.len();
         tokens.splice(start_index..start_index, new_tox);
         Some(new_len)
@@ -39,16 +392,20 @@ pub fn replace_first_match<T>(
     transform: &dyn Transform<T>,
     tokens: &mut Vec<Token<'_, T>>,
 ) -> bool {
-    let mut start_index = 0usize;
-    while start_index < tokens.len() {
-        let inc = test_and_transform(seq, transform, tokens, start_index);
-        if inc.is_some() {
-            return true;
-        } else {
-            start_index += 1;
-        }
+    match first_match(seq, tokens, 0) {
+        Some(start_index) => test_and_transform(seq, transform, tokens, start_index).is_some(),
+        None => false,
     }
-    false
+}
+
+/// The first index from `start_index` on where `seq` matches.
+pub fn first_match<T>(
+    seq: &dyn Sequence<T>,
+    tokens: &[Token<'_, T>],
+    start_index: usize,
+) -> Option<usize> {
+    let refs = tokens.iter().collect::<Vec<_>>();
+    (start_index..refs.len()).find(|&i| seq.match_tokens(&refs[i..]).is_some())
 }
 
 pub fn replace_all_matches<T>(
@@ -70,14 +427,10 @@ pub fn replace_all_matches_once<T>(
 ) -> bool {
     let mut start_index = 0usize;
     let mut changed = false;
-    while start_index < tokens.len() {
-        let inc = test_and_transform(seq, transform, tokens, start_index);
-        if let Some(match_size) = inc {
-            start_index += match_size;
-            changed = true;
-        } else {
-            start_index += 1;
-        }
+    while let Some(found) = first_match(seq, tokens, start_index) {
+        let inc = test_and_transform(seq, transform, tokens, found);
+        start_index = found + inc.unwrap_or(1);
+        changed = true;
     }
     changed
 }
@@ -120,6 +473,18 @@ impl<T> Sequence<T> for RawSeq<T> {
             None
         }
     }
+
+    fn expected(&self) -> Vec<Expected> {
+        vec![Expected::Literal(self.text.clone())]
+    }
+
+    fn first_set(&self) -> FirstSet {
+        FirstSet::of(self.expected())
+    }
+
+    fn reach(&self, tokens: &[&Token<T>]) -> (Option<usize>, usize) {
+        (self.match_tokens(tokens), 1)
+    }
 }
 
 #[derive(Clone)]
@@ -128,6 +493,7 @@ where
     F: Fn(&Token<'_, T>) -> bool,
 {
     pub predicate: F,
+    pub expected: Vec<Expected>,
     _t: PhantomData<T>,
 }
 
@@ -138,9 +504,18 @@ where
     pub fn new(predicate: F) -> FirstTokenSeq<T, F> {
         FirstTokenSeq {
             predicate,
+            expected: vec![],
             _t: PhantomData,
         }
     }
+
+    /// Describes what the predicate accepts, for use in diagnostics. If this
+    /// is used, it should cover everything the predicate accepts, since it
+    /// also decides where the sequence is tried at all.
+    pub fn expecting(mut self, expected: Expected) -> FirstTokenSeq<T, F> {
+        self.expected.push(expected);
+        self
+    }
 }
 
 impl<T, F> Sequence<T> for FirstTokenSeq<T, F>
@@ -154,63 +529,200 @@ where
             None
         }
     }
+
+    fn expected(&self) -> Vec<Expected> {
+        self.expected.clone()
+    }
+
+    fn first_set(&self) -> FirstSet {
+        if self.expected.is_empty() {
+            FirstSet::Any
+        } else {
+            FirstSet::of(self.expected.clone())
+        }
+    }
+
+    fn reach(&self, tokens: &[&Token<T>]) -> (Option<usize>, usize) {
+        (self.match_tokens(tokens), 1)
+    }
 }
 
-pub struct ChooseSeq<T> {
-    pub options: Vec<Box<dyn Sequence<T>>>,
+/// A sequence that may be shared between threads, which is what the `meta`
+/// compiler builds its sequences out of.
+pub type SyncSequence<T> = dyn Sequence<T> + Send + Sync;
+
+/// Matches the first of its options that matches. Like the other
+/// combinators, it holds its parts as `Box<S>`, which is `dyn Sequence<T>`
+/// unless it's made with `of`, e.g. to hold `SyncSequence`s instead.
+pub struct ChooseSeq<T, S: ?Sized = dyn Sequence<T>> {
+    pub options: Vec<Box<S>>,
+    _t: PhantomData<T>,
 }
 
 impl<T: 'static> ChooseSeq<T> {
     pub fn new(options: Vec<Box<dyn Sequence<T>>>) -> ChooseSeq<T> {
-        ChooseSeq { options }
+        ChooseSeq::of(options)
     }
 
     pub fn from_str(text: &str) -> ChooseSeq<T> {
-        ChooseSeq {
-            options: text
-                .chars()
+        ChooseSeq::of(
+            text.chars()
                 .map(|c| Box::new(RawSeq::new_from_owned(format!("{c}"))) as Box<dyn Sequence<T>>)
                 .collect(),
+        )
+    }
+}
+
+impl<T, S: ?Sized> ChooseSeq<T, S> {
+    pub fn of(options: Vec<Box<S>>) -> ChooseSeq<T, S> {
+        ChooseSeq {
+            options,
+            _t: PhantomData,
         }
     }
 }
 
-impl<T> Sequence<T> for ChooseSeq<T> {
+impl<T, S: Sequence<T> + ?Sized> Sequence<T> for ChooseSeq<T, S> {
     fn match_tokens(&self, tokens: &[&Token<T>]) -> Option<usize> {
         self.options.iter().find_map(|seq| seq.match_tokens(tokens))
     }
+
+    fn expected(&self) -> Vec<Expected> {
+        self.options.iter().flat_map(|seq| seq.expected()).collect()
+    }
+
+    fn first_set(&self) -> FirstSet {
+        self.options
+            .iter()
+            .fold(FirstSet::of(vec![]), |set, seq| set.or(seq.first_set()))
+    }
+
+    fn diagnose(&self, tokens: &[&Token<T>]) -> Result<usize, Failure> {
+        let mut furthest: Option<Failure> = None;
+        for seq in &self.options {
+            match seq.diagnose(tokens) {
+                Ok(len) => return Ok(len),
+                Err(f) => {
+                    furthest = Some(match furthest {
+                        Some(prev) => prev.furthest(f),
+                        None => f,
+                    })
+                }
+            }
+        }
+        Err(furthest.unwrap_or_else(|| Failure::new(0, vec![])))
+    }
+
+    fn reach(&self, tokens: &[&Token<T>]) -> (Option<usize>, usize) {
+        let mut reach = 0;
+        for seq in &self.options {
+            let (res, r) = seq.reach(tokens);
+            reach = reach.max(r);
+            if res.is_some() {
+                return (res, reach);
+            }
+        }
+        (None, reach)
+    }
+
+    fn capture_tokens(
+        &self,
+        tokens: &[&Token<T>],
+        offset: usize,
+        captures: &mut Vec<Capture>,
+    ) -> Option<usize> {
+        let before = captures.len();
+        for seq in &self.options {
+            if let Some(len) = seq.capture_tokens(tokens, offset, captures) {
+                return Some(len);
+            }
+            captures.truncate(before);
+        }
+        None
+    }
 }
 
-pub struct OptionalSeq<T> {
-    pub option: Box<dyn Sequence<T>>,
+pub struct OptionalSeq<T, S: ?Sized = dyn Sequence<T>> {
+    pub option: Box<S>,
+    _t: PhantomData<T>,
 }
 
 impl<T: 'static> OptionalSeq<T> {
     pub fn new(option: Box<dyn Sequence<T>>) -> OptionalSeq<T> {
-        OptionalSeq { option }
+        OptionalSeq::of(option)
+    }
+}
+
+impl<T, S: ?Sized> OptionalSeq<T, S> {
+    pub fn of(option: Box<S>) -> OptionalSeq<T, S> {
+        OptionalSeq {
+            option,
+            _t: PhantomData,
+        }
     }
 }
 
-impl<T> Sequence<T> for OptionalSeq<T> {
+impl<T, S: Sequence<T> + ?Sized> Sequence<T> for OptionalSeq<T, S> {
     fn match_tokens(&self, tokens: &[&Token<T>]) -> Option<usize> {
         match self.option.match_tokens(tokens) {
             None => Some(0),
             Some(len) => Some(len),
         }
     }
+
+    fn expected(&self) -> Vec<Expected> {
+        self.option.expected()
+    }
+
+    fn first_set(&self) -> FirstSet {
+        self.option.first_set().optional()
+    }
+
+    fn reach(&self, tokens: &[&Token<T>]) -> (Option<usize>, usize) {
+        let (res, reach) = self.option.reach(tokens);
+        (Some(res.unwrap_or(0)), reach)
+    }
+
+    fn capture_tokens(
+        &self,
+        tokens: &[&Token<T>],
+        offset: usize,
+        captures: &mut Vec<Capture>,
+    ) -> Option<usize> {
+        let before = captures.len();
+        match self.option.capture_tokens(tokens, offset, captures) {
+            None => {
+                captures.truncate(before);
+                Some(0)
+            }
+            Some(len) => Some(len),
+        }
+    }
 }
 
-pub struct RepeatedSeq<T> {
-    pub to_repeat: Box<dyn Sequence<T>>,
+/// Matches its sequence as many times in a row as it can. Stops at the first
+/// time it matches no tokens, since it would match nothing forever after.
+pub struct RepeatedSeq<T, S: ?Sized = dyn Sequence<T>> {
+    pub to_repeat: Box<S>,
+    _t: PhantomData<T>,
 }
 
 impl<T> RepeatedSeq<T> {
     pub fn new(to_repeat: Box<dyn Sequence<T>>) -> RepeatedSeq<T> {
-        RepeatedSeq { to_repeat }
+        RepeatedSeq::of(to_repeat)
+    }
+}
+
+impl<T, S: ?Sized> RepeatedSeq<T, S> {
+    pub fn of(to_repeat: Box<S>) -> RepeatedSeq<T, S> {
+        RepeatedSeq {
+            to_repeat,
+            _t: PhantomData,
+        }
     }
 }
 
-impl<T> Sequence<T> for RepeatedSeq<T> {
+impl<T, S: Sequence<T> + ?Sized> Sequence<T> for RepeatedSeq<T, S> {
     fn match_tokens(&self, tokens: &[&Token<T>]) -> Option<usize> {
         let mut index = 0usize;
         loop {
@@ -219,26 +731,86 @@ impl<T> Sequence<T> for RepeatedSeq<T> {
             }
 
             let res = self.to_repeat.match_tokens(&tokens[index..]);
-            if let Some(len) = res {
-                index += len;
-            } else {
-                return Some(index);
+            match res {
+                Some(len) if len > 0 => index += len,
+                _ => return Some(index),
+            }
+        }
+    }
+
+    fn expected(&self) -> Vec<Expected> {
+        self.to_repeat.expected()
+    }
+
+    fn first_set(&self) -> FirstSet {
+        self.to_repeat.first_set().optional()
+    }
+
+    fn reach(&self, tokens: &[&Token<T>]) -> (Option<usize>, usize) {
+        let mut index = 0usize;
+        let mut reach = 0;
+        loop {
+            if index > tokens.len() {
+                return (Some(tokens.len()), reach);
+            }
+
+            let (res, r) = self.to_repeat.reach(&tokens[index..]);
+            reach = reach.max(index + r);
+            match res {
+                Some(len) if len > 0 => index += len,
+                _ => return (Some(index), reach),
+            }
+        }
+    }
+
+    fn capture_tokens(
+        &self,
+        tokens: &[&Token<T>],
+        offset: usize,
+        captures: &mut Vec<Capture>,
+    ) -> Option<usize> {
+        let mut index = 0usize;
+        loop {
+            if index > tokens.len() {
+                return Some(tokens.len());
+            }
+
+            let before = captures.len();
+            let res = self
+                .to_repeat
+                .capture_tokens(&tokens[index..], offset + index, captures);
+            match res {
+                Some(len) if len > 0 => index += len,
+                _ => {
+                    captures.truncate(before);
+                    return Some(index);
+                }
             }
         }
     }
 }
 
-pub struct MultipleSeq<T> {
-    pub seqs: Vec<Box<dyn Sequence<T>>>,
+pub struct MultipleSeq<T, S: ?Sized = dyn Sequence<T>> {
+    pub seqs: Vec<Box<S>>,
+    _t: PhantomData<T>,
 }
 
 impl<T> MultipleSeq<T> {
     pub fn new(seqs: Vec<Box<dyn Sequence<T>>>) -> MultipleSeq<T> {
-        MultipleSeq { seqs }
+        MultipleSeq::of(seqs)
+    }
+}
+
+impl<T, S: ?Sized> MultipleSeq<T, S> {
+    pub fn of(seqs: Vec<Box<S>>) -> MultipleSeq<T, S> {
+        MultipleSeq {
+            seqs,
+            _t: PhantomData,
+        }
     }
 }
 
-impl<T> Sequence<T> for MultipleSeq<T> {
+impl<T, S: Sequence<T> + ?Sized> Sequence<T> for MultipleSeq<T, S> {
     fn match_tokens(&self, tokens: &[&Token<T>]) -> Option<usize> {
         let mut index = 0usize;
         for seq in &self.seqs {
@@ -256,4 +828,251 @@ impl<T> Sequence<T> for MultipleSeq<T> {
         }
         Some(index)
     }
+
+    fn expected(&self) -> Vec<Expected> {
+        self.seqs
+            .first()
+            .map(|seq| seq.expected())
+            .unwrap_or_default()
+    }
+
+    fn first_set(&self) -> FirstSet {
+        self.seqs
+            .iter()
+            .fold(FirstSet::nothing(), |set, seq| set.then(seq.first_set()))
+    }
+
+    fn reach(&self, tokens: &[&Token<T>]) -> (Option<usize>, usize) {
+        let mut index = 0usize;
+        let mut reach = 0;
+        for seq in &self.seqs {
+            if index > tokens.len() {
+                return (None, reach);
+            }
+
+            let (res, r) = seq.reach(&tokens[index..]);
+            reach = reach.max(index + r);
+            match res {
+                Some(len) => index += len,
+                None => return (None, reach),
+            }
+        }
+        (Some(index), reach)
+    }
+
+    fn diagnose(&self, tokens: &[&Token<T>]) -> Result<usize, Failure> {
+        let mut index = 0usize;
+        for (i, seq) in self.seqs.iter().enumerate() {
+            match seq.diagnose(&tokens[index.min(tokens.len())..]) {
+                Ok(len) => index += len,
+                Err(f) => {
+                    let after = if f.position == 0 && i > 0 {
+                        self.seqs[i - 1].expected()
+                    } else {
+                        f.after
+                    };
+                    return Err(Failure {
+                        position: index + f.position,
+                        expected: f.expected,
+                        after,
+                    });
+                }
+            }
+        }
+        Ok(index)
+    }
+
+    fn capture_tokens(
+        &self,
+        tokens: &[&Token<T>],
+        offset: usize,
+        captures: &mut Vec<Capture>,
+    ) -> Option<usize> {
+        let mut index = 0usize;
+        for seq in &self.seqs {
+            if index > tokens.len() {
+                return None;
+            }
+
+            index += seq.capture_tokens(&tokens[index..], offset + index, captures)?;
+        }
+        Some(index)
+    }
+
+    fn match_elements(&self, tokens: &[&Token<T>]) -> Option<Match> {
+        let mut index = 0usize;
+        let mut elements = vec![];
+        let mut captures = vec![];
+        for seq in &self.seqs {
+            if index > tokens.len() {
+                return None;
+            }
+
+            let len = seq.capture_tokens(&tokens[index..], index, &mut captures)?;
+            elements.push(index..index + len);
+            index += len;
+        }
+        Some(Match {
+            len: index,
+            elements,
+            captures,
+        })
+    }
+}
+
+/// Matches no tokens, but only if `inner` doesn't match.
+pub struct NotSeq<T, S: ?Sized = dyn Sequence<T>> {
+    pub inner: Box<S>,
+    _t: PhantomData<T>,
+}
+
+impl<T> NotSeq<T> {
+    pub fn new(inner: Box<dyn Sequence<T>>) -> NotSeq<T> {
+        NotSeq::of(inner)
+    }
+}
+
+impl<T, S: ?Sized> NotSeq<T, S> {
+    pub fn of(inner: Box<S>) -> NotSeq<T, S> {
+        NotSeq {
+            inner,
+            _t: PhantomData,
+        }
+    }
+}
+
+impl<T, S: Sequence<T> + ?Sized> Sequence<T> for NotSeq<T, S> {
+    fn match_tokens(&self, tokens: &[&Token<T>]) -> Option<usize> {
+        match self.inner.match_tokens(tokens) {
+            Some(_) => None,
+            None => Some(0),
+        }
+    }
+
+    fn first_set(&self) -> FirstSet {
+        FirstSet::nothing()
+    }
+
+    fn reach(&self, tokens: &[&Token<T>]) -> (Option<usize>, usize) {
+        let (res, reach) = self.inner.reach(tokens);
+        (if res.is_some() { None } else { Some(0) }, reach)
+    }
+}
+
+/// Matches whatever `inner` matches, remembering where it matched.
+pub struct CaptureSeq<T, S: ?Sized = dyn Sequence<T>> {
+    pub name: Option<String>,
+    pub inner: Box<S>,
+    _t: PhantomData<T>,
+}
+
+impl<T> CaptureSeq<T> {
+    pub fn new(name: Option<String>, inner: Box<dyn Sequence<T>>) -> CaptureSeq<T> {
+        CaptureSeq::of(name, inner)
+    }
+}
+
+impl<T, S: ?Sized> CaptureSeq<T, S> {
+    pub fn of(name: Option<String>, inner: Box<S>) -> CaptureSeq<T, S> {
+        CaptureSeq {
+            name,
+            inner,
+            _t: PhantomData,
+        }
+    }
+}
+
+impl<T, S: Sequence<T> + ?Sized> Sequence<T> for CaptureSeq<T, S> {
+    fn match_tokens(&self, tokens: &[&Token<T>]) -> Option<usize> {
+        self.inner.match_tokens(tokens)
+    }
+
+    fn expected(&self) -> Vec<Expected> {
+        self.inner.expected()
+    }
+
+    fn diagnose(&self, tokens: &[&Token<T>]) -> Result<usize, Failure> {
+        self.inner.diagnose(tokens)
+    }
+
+    fn first_set(&self) -> FirstSet {
+        self.inner.first_set()
+    }
+
+    fn reach(&self, tokens: &[&Token<T>]) -> (Option<usize>, usize) {
+        self.inner.reach(tokens)
+    }
+
+    fn capture_tokens(
+        &self,
+        tokens: &[&Token<T>],
+        offset: usize,
+        captures: &mut Vec<Capture>,
+    ) -> Option<usize> {
+        let len = self.inner.capture_tokens(tokens, offset, captures)?;
+        captures.push(Capture {
+            name: self.name.clone(),
+            range: offset..offset + len,
+        });
+        Some(len)
+    }
+}
+
+pub type MatchGuard<T> = dyn Fn(&[&Token<T>], &Match) -> bool;
+
+/// Matches whatever `inner` matches, but only if `guard` accepts the match.
+/// The guard is expected to only look at the matched tokens.
+pub struct GuardSeq<T, S: ?Sized = dyn Sequence<T>, G: ?Sized = MatchGuard<T>> {
+    pub inner: Box<S>,
+    pub guard: Box<G>,
+    _t: PhantomData<T>,
+}
+
+impl<T> GuardSeq<T> {
+    pub fn new(
+        inner: Box<dyn Sequence<T>>,
+        guard: impl Fn(&[&Token<T>], &Match) -> bool + 'static,
+    ) -> GuardSeq<T> {
+        GuardSeq::of(inner, Box::new(guard))
+    }
+}
+
+impl<T, S: ?Sized, G: ?Sized> GuardSeq<T, S, G> {
+    pub fn of(inner: Box<S>, guard: Box<G>) -> GuardSeq<T, S, G> {
+        GuardSeq {
+            inner,
+            guard,
+            _t: PhantomData,
+        }
+    }
+}
+
+impl<T, S, G> Sequence<T> for GuardSeq<T, S, G>
+where
+    S: Sequence<T> + ?Sized,
+    G: Fn(&[&Token<T>], &Match) -> bool + ?Sized,
+{
+    fn match_tokens(&self, tokens: &[&Token<T>]) -> Option<usize> {
+        self.match_elements(tokens).map(|m| m.len)
+    }
+
+    fn expected(&self) -> Vec<Expected> {
+        self.inner.expected()
+    }
+
+    fn match_elements(&self, tokens: &[&Token<T>]) -> Option<Match> {
+        let m = self.inner.match_elements(tokens)?;
+        (self.guard)(tokens, &m).then_some(m)
+    }
+
+    fn first_set(&self) -> FirstSet {
+        self.inner.first_set()
+    }
+
+    fn reach(&self, tokens: &[&Token<T>]) -> (Option<usize>, usize) {
+        match self.inner.reach(tokens) {
+            (Some(_), reach) => (self.match_tokens(tokens), reach),
+            none => none,
+        }
+    }
 }
@@ -42,6 +42,23 @@ pub fn rep_seq_test(text: &str, should_match: bool) {
     assert_match(seq, text, should_match);
 }
 
+#[test_case("aa", true; "repeating the option")]
+#[test_case("", true; "empty passing case")]
+#[test_case("b", false; "incorrect string")]
+pub fn rep_nullable_seq_test(text: &str, should_match: bool) {
+    let seq = RepeatedSeq::new(Box::new(OptionalSeq::new(Box::new(RawSeq::new("a")))));
+    assert_match(seq, text, should_match);
+}
+
+#[test]
+pub fn rep_nullable_reach_test() {
+    let seq = RepeatedSeq::new(Box::new(OptionalSeq::new(Box::new(RawSeq::new("a")))));
+    let tox = Token::token_vec_from_str("aab", &|_, _| ());
+    let refs = tox.iter().collect::<Vec<_>>();
+    assert_eq!(seq.reach(&refs), (Some(2), 3));
+    assert_eq!(seq.match_elements(&refs).map(|m| m.len), Some(2));
+}
+
 #[test_case("ab", true; "basic passing case")]
 #[test_case("", false; "empty failing case")]
 #[test_case("aa", false; "multiple of incorrect token")]
@@ -51,3 +68,135 @@ pub fn mult_seq_test(text: &str, should_match: bool) {
     let seq = MultipleSeq::new(vec![Box::new(RawSeq::new("a")), Box::new(RawSeq::new("b"))]);
     assert_match(seq, text, should_match);
 }
+
+#[test_case("ab", None; "full match")]
+#[test_case("b", Some(0); "fails on first token")]
+#[test_case("a", Some(1); "runs out of tokens")]
+#[test_case("ac", Some(1); "fails on second token")]
+pub fn mult_seq_diagnose_test(text: &str, failed_at: Option<usize>) {
+    let seq: MultipleSeq<()> =
+        MultipleSeq::new(vec![Box::new(RawSeq::new("a")), Box::new(RawSeq::new("b"))]);
+    let tox = Token::token_vec_from_str(text, |_, _| ());
+    let res = seq.diagnose(&tox.iter().collect::<Vec<_>>());
+    assert_eq!(res.err().map(|f| f.position), failed_at);
+}
+
+#[test]
+pub fn choose_seq_diagnose_test() {
+    let seq: ChooseSeq<()> = ChooseSeq::new(vec![
+        Box::new(MultipleSeq::new(vec![
+            Box::new(RawSeq::new("a")),
+            Box::new(RawSeq::new("b")),
+        ])),
+        Box::new(MultipleSeq::new(vec![
+            Box::new(RawSeq::new("a")),
+            Box::new(RawSeq::new("c")),
+        ])),
+    ]);
+    let tox = Token::token_vec_from_str("ad", |_, _| ());
+    let failure = seq.diagnose(&tox.iter().collect::<Vec<_>>()).unwrap_err();
+    assert_eq!(failure.position, 1);
+    assert_eq!(
+        failure.expected,
+        vec![
+            Expected::Literal("b".to_string()),
+            Expected::Literal("c".to_string())
+        ]
+    );
+    assert_eq!(failure.after, vec![Expected::Literal("a".to_string())]);
+}
+
+#[test]
+pub fn match_elements_test() {
+    let seq: MultipleSeq<()> = MultipleSeq::new(vec![
+        Box::new(RepeatedSeq::new(Box::new(RawSeq::new("a")))),
+        Box::new(CaptureSeq::new(
+            Some("b".to_string()),
+            Box::new(RawSeq::new("b")),
+        )),
+        Box::new(OptionalSeq::new(Box::new(CaptureSeq::new(
+            None,
+            Box::new(RawSeq::new("c")),
+        )))),
+    ]);
+    let tox = Token::token_vec_from_str("aab", |_, _| ());
+    let m = seq.match_elements(&tox.iter().collect::<Vec<_>>()).unwrap();
+    assert_eq!(m.len, 3);
+    assert_eq!(m.elements, vec![0..2, 2..3, 3..3]);
+    assert_eq!(
+        m.captures,
+        vec![Capture {
+            name: Some("b".to_string()),
+            range: 2..3
+        }]
+    );
+}
+
+#[test_case("ab", Some(0); "not followed by b")]
+#[test_case("ba", None; "followed by b")]
+#[test_case("", Some(0); "no letters")]
+pub fn not_seq_test(text: &str, expected: Option<usize>) {
+    let seq: NotSeq<()> = NotSeq::new(Box::new(RawSeq::new("b")));
+    let tox = Token::token_vec_from_str(text, |_, _| ());
+    let refs: Vec<&Token<()>> = tox.iter().collect();
+    assert_eq!(seq.match_tokens(&refs), expected);
+}
+
+#[test_case("ab", (Some(2), 2); "matching")]
+#[test_case("ac", (None, 2); "failing on the second token")]
+#[test_case("c", (None, 1); "failing on the first token")]
+#[test_case("a", (None, 2); "running off the end")]
+pub fn reach_test(text: &str, expected: (Option<usize>, usize)) {
+    let seq: MultipleSeq<()> = MultipleSeq::new(vec![
+        Box::new(RawSeq::new("a")),
+        Box::new(OptionalSeq::new(Box::new(RawSeq::new("x")))),
+        Box::new(RawSeq::new("b")),
+    ]);
+    let tox = Token::token_vec_from_str(text, |_, _| ());
+    let refs: Vec<&Token<()>> = tox.iter().collect();
+    assert_eq!(seq.reach(&refs), expected);
+}
+
+#[test]
+pub fn first_set_test() {
+    let seq: MultipleSeq<()> = MultipleSeq::new(vec![
+        Box::new(OptionalSeq::new(Box::new(RawSeq::new("-")))),
+        Box::new(NotSeq::new(Box::new(RawSeq::new("0")))),
+        Box::new(ChooseSeq::from_str("12")),
+        Box::new(RawSeq::new("x")),
+    ]);
+    assert_eq!(
+        seq.first_set(),
+        FirstSet::of(vec![
+            Expected::Literal("-".to_string()),
+            Expected::Literal("1".to_string()),
+            Expected::Literal("2".to_string()),
+        ])
+    );
+    assert!(seq.first_set().admits("2", |_| false));
+    assert!(!seq.first_set().admits("x", |_| false));
+}
+
+#[test_case(FirstSet::Any, "q", true; "anything")]
+#[test_case(FirstSet::of(vec![Expected::Range('a', 'f')]), "c", true; "in a range")]
+#[test_case(FirstSet::of(vec![Expected::Range('a', 'f')]), "cd", false; "too long for a range")]
+#[test_case(FirstSet::of(vec![Expected::Tag("int".to_string())]), "1", true; "by tag")]
+#[test_case(FirstSet::of(vec![Expected::Literal("a".to_string())]).optional(), "b", true; "nullable")]
+pub fn admits_test(set: FirstSet, content: &str, expected: bool) {
+    assert_eq!(set.admits(content, |t| t == "int"), expected);
+}
+
+/// A sequence that can't be shared between threads.
+struct RcSeq(std::rc::Rc<str>);
+
+impl Sequence<()> for RcSeq {
+    fn match_tokens(&self, tokens: &[&Token<()>]) -> Option<usize> {
+        (tokens.first()?.content() == &*self.0).then_some(1)
+    }
+}
+
+#[test]
+pub fn unshared_seq_test() {
+    let seq = RepeatedSeq::new(Box::new(RcSeq("a".into())));
+    assert_match(seq, "aa", true);
+}
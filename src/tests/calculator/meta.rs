@@ -1,11 +1,13 @@
-use crate::meta::{eval_prog_from_text, graph_with_tags};
+use crate::arena::TokenStore;
+use crate::meta::{
+    char_to_token, eval_prog_from_text, eval_prog_with_diagnostics, graph_with_tags, prog_from_str,
+    Budget, ExecutionLimits, TextEdit,
+};
 
 use super::*;
 use test_case::test_case;
 
-pub fn calc_tokens<'a>(text: &str) -> Vec<Token<Vec<String>>> {
-    meta::eval_prog_from_text(
-        "
+pub const CALC_PROG: &str = "
          ## recognize words
             (e.g. sqrt, abs)
          ##
@@ -19,12 +21,12 @@ pub fn calc_tokens<'a>(text: &str) -> Vec<Token<Vec<String>>> {
          ##
         1..9. nonzero, digit;
         0. digit;
-        nonzero & digit*. int, positive, number, expr;
-        '0'. int, positive, number, expr;
+        nonzero & digit*. int, positive, number, expr => value(f64);
+        '0'. int, positive, number, expr => value(f64);
 
           # recognize decimals and negative numbers
-        int & '.' & int+. decimal, positive, number, expr;
-        '-' & positive: negative, number, expr;
+        int & '.' & int+. decimal, positive, number, expr => value(f64);
+        '-' & positive: negative, number, expr => value(f64);
 
           # remove whitespace
         ws~;
@@ -39,9 +41,31 @@ pub fn calc_tokens<'a>(text: &str) -> Vec<Token<Vec<String>>> {
             expr & '*' | '/' & expr: oper, expr;
             expr & '+' | '-' & expr: oper, expr;
         }
-    ",
-        text,
-    )
+    ";
+
+/// The calculator with its operators declared with `precedence`, along with
+/// a right-associative power operator and a prefix minus in place of
+/// negative numbers.
+pub const PREC_PROG: &str = "
+        % {
+        a..z | A..Z | '_'. letter;
+        letter+. word;
+        1..9. nonzero, digit;
+        0. digit;
+        nonzero & digit*. int, positive, number, expr => value(f64);
+        '0'. int, positive, number, expr => value(f64);
+        int & '.' & int+. decimal, positive, number, expr => value(f64);
+        ws~;
+        }
+        {
+            '(' & expr & ')': parens, expr;
+            word & parens: call, expr;
+            precedence expr { right '^'; prefix '-'; left '*' '/'; left '+' '-'; }
+        }
+    ";
+
+pub fn calc_tokens<'a>(text: &str) -> Vec<Token<Vec<String>>> {
+    meta::eval_prog_from_text(CALC_PROG, text)
 }
 
 pub fn eval(token: &Token<'_, Vec<String>>) -> Option<f64> {
@@ -54,15 +78,16 @@ pub fn eval(token: &Token<'_, Vec<String>>) -> Option<f64> {
             None
         }
     } else if token.data.contains(&"number".to_string()) {
-        Some(
-            token
-                .content()
-                .parse()
-                .expect("f64 was recognized as correct but didn't parse in Rust"),
-        )
+        token.value::<f64>().copied()
+    } else if token.data.contains(&"prefix".to_string()) {
+        match token.nth_child(0)?.content() {
+            "-" => Some(-eval(token.nth_child(1)?)?),
+            _ => None,
+        }
     } else if token.data.contains(&"oper".to_string()) {
         if let TokenType::Branch(children) = &token.t_type {
             match children.get(1)?.content() {
+                "^" => Some(eval(children.first()?)?.powf(eval(children.get(2)?)?)),
                 "+" => Some(eval(children.get(0)?)? + eval(children.get(2)?)?),
                 "-" => Some(eval(children.get(0)?)? - eval(children.get(2)?)?),
                 "*" => Some(eval(children.get(0)?)? * eval(children.get(2)?)?),
@@ -117,6 +142,7 @@ pub fn eval_text(text: &str) -> Option<f64> {
 #[test_case("6 / 3", Some(2.0); "basic integer division")]
 #[test_case("1 + 2 * 3 + 1", Some(8.0); "order of operations")]
 #[test_case("(1)", Some(1.0); "basic parenthetical")]
+#[test_case("8 - 4 - 2", Some(2.0); "left-associative subtraction")]
 #[test_case("1 + 1 * ((((50))))", Some(51.0); "order of operations with nested parens")]
 #[test_case("((((50)))) * 1 + 1", Some(51.0); "order of operations with nested parens, backwards")]
 #[test_case("sqrt(1)", Some(1.0); "basic application")]
@@ -125,3 +151,213 @@ pub fn eval_text(text: &str) -> Option<f64> {
 pub fn eval_test(text: &str, expected: Option<f64>) {
     assert_eq!(eval_text(text), expected)
 }
+
+#[test_case("1 + 2 * 3 + 1", Some(8.0); "order of operations")]
+#[test_case("8 - 4 - 2", Some(2.0); "left-associative subtraction")]
+#[test_case("2 ^ 3 ^ 2", Some(512.0); "right-associative powers")]
+#[test_case("-2 ^ 2", Some(-4.0); "prefix minus looser than powers")]
+#[test_case("-2 * 3", Some(-6.0); "prefix minus tighter than products")]
+#[test_case("- -3", Some(3.0); "repeated prefix minus")]
+#[test_case("1 - -2", Some(3.0); "prefix minus after a binary one")]
+#[test_case("1 + 2 * (3 + 4)", Some(15.0); "order of operations around parens")]
+#[test_case("2 * (3 + 4) ^ 2", Some(98.0); "powers of parens")]
+#[test_case("sqrt(16) ^ 2 - 1", Some(15.0); "powers of calls")]
+#[test_case("1 + * 2", None; "missing operand")]
+pub fn precedence_test(text: &str, expected: Option<f64>) {
+    assert_eq!(eval_first(&eval_prog_from_text(PREC_PROG, text)), expected);
+}
+
+#[test]
+pub fn precedence_tags_test() {
+    let tokens = eval_prog_from_text(PREC_PROG, "-1 ^ 2");
+    assert_eq!(tokens.len(), 1);
+    assert_eq!(
+        tokens[0].data,
+        vec!["expr", "oper", "prefix", "-", "level2"]
+    );
+    let power = tokens[0].nth_child(1).unwrap();
+    assert_eq!(power.data, vec!["expr", "oper", "binary", "^", "level1"]);
+}
+
+#[test_case("sqrt(abs(ln(1)", Some("expected ')' after expr at 1:15"); "no trailing end-parens")]
+#[test_case("(1 +", Some("expected expr after '+' or '-' at 1:5"); "missing operand")]
+#[test_case("1 +\n  (2", Some("expected ')' after expr at 2:5"); "second line")]
+#[test_case("sqrt(abs(ln(1)))", None; "nested applications")]
+#[test_case("1 + 2 * 3", None; "order of operations")]
+pub fn diagnostic_test(text: &str, expected: Option<&str>) {
+    let res = eval_prog_with_diagnostics(CALC_PROG, text);
+    assert_eq!(
+        res.diagnostics.first().map(|d| d.to_string()),
+        expected.map(|s| s.to_string())
+    );
+}
+
+#[test]
+pub fn long_expression_test() {
+    let text = vec!["12"; 300].join(" + ");
+    assert_eq!(eval_text(&text), Some(3600.0));
+}
+
+/// A token store that counts the tokens the engine asks for, whether to
+/// match them or to replace them.
+struct Counting<'a> {
+    tokens: Vec<Token<'a, Vec<String>>>,
+    touched: usize,
+}
+
+impl<'a> TokenStore<'a, Vec<String>> for Counting<'a> {
+    fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    fn with_window<R>(
+        &mut self,
+        start: usize,
+        len: usize,
+        f: impl FnOnce(&[&Token<'a, Vec<String>>]) -> R,
+    ) -> R {
+        self.tokens.with_window(start, len, |window| {
+            self.touched += window.len();
+            f(window)
+        })
+    }
+
+    fn replace(
+        &mut self,
+        start: usize,
+        len: usize,
+        f: impl FnOnce(Vec<Token<'a, Vec<String>>>) -> Vec<Token<'a, Vec<String>>>,
+    ) -> usize {
+        let new_len = self.tokens.replace(start, len, f);
+        self.touched += len + new_len;
+        new_len
+    }
+}
+
+/// How many tokens running `CALC_PROG` on `text` looks at. Its operators are
+/// plain rules in a group, so this measures the group's worklists, not a
+/// `precedence` declaration, which never rescans anything.
+fn touched(text: &str) -> usize {
+    let prog = prog_from_str(CALC_PROG).unwrap();
+    assert!(!CALC_PROG.contains("precedence"));
+    let mut store = Counting {
+        tokens: Token::token_vec_from_str(text, |r, i| {
+            char_to_token(r[i..].chars().next().unwrap())
+        }),
+        touched: 0,
+    };
+    let mut budget = Budget::new(&ExecutionLimits::default());
+    for rep in &prog.reps {
+        rep.execute(&mut store, &mut budget).unwrap();
+    }
+    assert_eq!(store.tokens.len(), 1);
+    store.touched
+}
+
+#[test_case("12", " + "; "a flat expression")]
+#[test_case("(12)", " * "; "parenthesized operands")]
+#[test_case("sqrt(1)", " - "; "calls")]
+pub fn linear_work_test(operand: &str, operator: &str) {
+    let short = touched(&vec![operand; 200].join(operator));
+    let long = touched(&vec![operand; 400].join(operator));
+    assert!(
+        long <= short * 21 / 10,
+        "{short} tokens looked at, then {long}"
+    );
+}
+
+fn shape(tokens: &[Token<'_, Vec<String>>]) -> Vec<String> {
+    tokens
+        .iter()
+        .flat_map(|token| {
+            let mut lines = vec![format!(
+                "{:?} {:?} {:?}",
+                token.content_range(),
+                token.data,
+                token.value::<f64>()
+            )];
+            if let Some(children) = token.children() {
+                lines.extend(shape(children).into_iter().map(|l| format!("  {l}")));
+            }
+            lines
+        })
+        .collect()
+}
+
+#[test_case("1 + 2 * 3", 8..9, "4"; "changing a number")]
+#[test_case("1 + 2 * 3", 6..7, "+"; "changing an operator")]
+#[test_case("1 + 2 + 3", 6..7, "*"; "changing precedence")]
+#[test_case("12 + 3", 2..2, "3"; "extending a number")]
+#[test_case("1 + 2", 1..4, ""; "joining numbers")]
+#[test_case("sqrt(abs(4)) * 2", 4..4, " "; "inserting whitespace")]
+#[test_case("sqrt(abs(4)) * 2", 9..10, "-4"; "inside nested calls")]
+#[test_case("1 + 2", 5..5, " * 3"; "appending")]
+#[test_case("1 + 2", 0..0, "(3) - "; "prepending")]
+#[test_case("1 +", 3..3, " 2"; "fixing an error")]
+#[test_case("1 + 2", 0..5, "7"; "replacing everything")]
+pub fn reparse_test(text: &str, range: std::ops::Range<usize>, replacement: &str) {
+    let prog = prog_from_str(CALC_PROG).unwrap();
+    let edit = TextEdit::new(range, replacement);
+    let new_text = edit.apply(text);
+    let reparsed = prog.reparse(prog.eval(text), &edit, &new_text);
+    let expected = prog.eval(&new_text);
+    assert_eq!(shape(&reparsed.tokens), shape(&expected.tokens));
+    assert_eq!(
+        reparsed
+            .diagnostics
+            .iter()
+            .map(|d| d.to_string())
+            .collect::<Vec<_>>(),
+        expected
+            .diagnostics
+            .iter()
+            .map(|d| d.to_string())
+            .collect::<Vec<_>>()
+    );
+}
+
+#[test]
+pub fn long_reparse_test() {
+    let prog = prog_from_str(CALC_PROG).unwrap();
+    let mut texts = vec![vec!["12"; 300].join(" + ")];
+    let edits = [
+        TextEdit::new(0..0, "3 * "),
+        TextEdit::new(757..758, "-"),
+        TextEdit::new(texts[0].len() + 4..texts[0].len() + 4, " / 4"),
+    ];
+    for edit in &edits {
+        texts.push(edit.apply(texts.last().unwrap()));
+    }
+    let mut result = prog.eval(&texts[0]);
+    for (edit, text) in edits.iter().zip(&texts[1..]) {
+        result = prog.reparse(result, edit, text);
+        assert_eq!(shape(&result.tokens), shape(&prog.eval(text).tokens));
+    }
+    assert_eq!(eval_first(&result.tokens), Some(3591.0));
+}
+
+#[test_case("1 + 2 * 3 + 1"; "order of operations")]
+#[test_case("sqrt(abs(ln(1)))"; "nested applications")]
+#[test_case("-1 +"; "missing operand")]
+pub fn arena_test(text: &str) {
+    let prog = prog_from_str(CALC_PROG).unwrap();
+    let tokens = prog.eval_arena(text).into_tokens();
+    assert_eq!(shape(&tokens), shape(&prog.eval(text).tokens));
+    assert_eq!(eval_first(&tokens), eval_text(text));
+}
+
+/// An arena is only worth it if each rewrite finds its place near the last
+/// one, so the links followed should grow with the text, not its square.
+#[test]
+pub fn arena_hops_test() {
+    let prog = prog_from_str(CALC_PROG).unwrap();
+    let hops = |terms: usize| {
+        let text = vec!["12"; terms].join(" + ");
+        prog.eval_arena(&text).hops()
+    };
+    let (short, long) = (hops(200), hops(400));
+    assert!(
+        long * 2 <= short * 5,
+        "{short} hops for 200 terms, {long} for 400"
+    );
+}
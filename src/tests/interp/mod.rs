@@ -1,6 +1,6 @@
 use std::collections::HashMap;
 
-use crate::meta::graph_with_tags;
+use crate::meta::{graph_with_tags, Diagnostic};
 
 use super::*;
 use test_case::test_case;
@@ -32,6 +32,8 @@ pub fn parse(text: &str) -> Vec<Token<Vec<String>>> {
             word & '=' & expr & ';': mutStmt, stmt;
             if & expr & '{' & stmt+ & '}': ifStmt, stmt;
         }
+
+        recover stmt until ';';
     ",
         text,
     )
@@ -88,6 +90,8 @@ pub fn eval_program(tokens: &Vec<Token<Vec<String>>>) -> Option<Program> {
     for token in tokens {
         if let Some(stmt) = eval_stmt(token) {
             to_ret.prog.push(stmt);
+        } else if let Some(diag) = token.value::<Diagnostic>() {
+            to_ret.errors.push(diag.to_string());
         } else {
             to_ret
                 .errors
@@ -350,3 +354,23 @@ pub fn run_prog_from_text(text: &str) {
         false,
     )
 }
+
+#[test_case("
+let a = ;
+print 3;
+", vec!["expected expr after '=' at 2:9"]; "missing expression")]
+#[test_case("
+print 1;
+print 2
+print 3;
+let b = 4;
+", vec!["expected ';' after expr at 3:8"]; "missing semicolon")]
+#[test_case("
+let a = 1;
+print a;
+", vec![]; "no errors")]
+pub fn recovery_test(text: &str, expected: Vec<&str>) {
+    let prog = eval_program(&parse(text)).unwrap();
+    assert_eq!(prog.errors, expected);
+    assert!(!prog.prog.is_empty());
+}
@@ -1,30 +1,41 @@
 pub use crate::*;
+pub use std::any::Any;
 pub use std::fmt::Write;
 pub use std::ops::Range;
 
+/// A semantic value carried by a token, e.g. the number a literal stands for.
+pub type Value = Box<dyn Any + Send + Sync>;
+
 #[derive(Debug)]
 pub struct Token<'a, T> {
     pub root: &'a str,
     pub t_type: TokenType<'a, T>,
     pub data: T,
+    pub value: Option<Value>,
 }
 
 #[derive(Debug)]
 pub enum TokenType<'a, T> {
     Leaf(Range<usize>),
     Branch(Vec<Token<'a, T>>),
+    /// A token that isn't in the text, sitting at the given position. It may
+    /// have some synthetic text of its own.
+    Virtual(usize, Option<String>),
 }
 
 impl<'a, T> Token<'a, T> {
+    /// A token for each character of `from`. `data` is given the text and
+    /// where the character starts in it, in bytes.
     pub fn token_vec_from_str(
         from: &'a str,
         data: impl Fn(&'a str, usize) -> T,
     ) -> Vec<Token<'a, T>> {
-        (0..from.len())
-            .map(|i| Token {
-                t_type: TokenType::Leaf(i..i + 1),
+        from.char_indices()
+            .map(|(i, c)| Token {
+                t_type: TokenType::Leaf(i..i + c.len_utf8()),
                 root: from,
                 data: data(from, i),
+                value: None,
             })
             .collect::<Vec<Token<T>>>()
     }
@@ -32,19 +43,30 @@ impl<'a, T> Token<'a, T> {
     pub fn content_range(&self) -> Range<usize> {
         match &self.t_type {
             TokenType::Leaf(r) => r.clone(),
-            TokenType::Branch(children) => {
-                children[0].content_range().start
-                    ..children
-                        .last()
-                        .expect("Branch tokens should have children!")
-                        .content_range()
-                        .end
-            }
+            TokenType::Virtual(at, _) => *at..*at,
+            // Children don't have to be in the order of the text, e.g. when a
+            // rule selects them out of order.
+            TokenType::Branch(children) => children
+                .iter()
+                .map(Token::content_range)
+                .reduce(|a, b| a.start.min(b.start)..a.end.max(b.end))
+                .expect("Branch tokens should have children!"),
         }
     }
 
+    /// The text the token covers. For a virtual token, that's its own text,
+    /// but a branch only covers the source text under it, so any virtual text
+    /// among its descendants isn't included.
     pub fn content(&self) -> &str {
-        &self.root[self.content_range()]
+        match &self.t_type {
+            TokenType::Virtual(_, text) => text.as_deref().unwrap_or(""),
+            _ => &self.root[self.content_range()],
+        }
+    }
+
+    /// The token's semantic value, if it has one of type `V`.
+    pub fn value<V: Any>(&self) -> Option<&V> {
+        self.value.as_ref()?.downcast_ref()
     }
 
     pub fn graph(&self) -> String {
@@ -84,6 +106,9 @@ impl<'a, T> Token<'a, T> {
             TokenType::Leaf(_) => {
                 writeln!(buf, "{}", self.content())?;
             }
+            TokenType::Virtual(_, _) => {
+                writeln!(buf, "<{}>", self.content())?;
+            }
         }
 
         Ok(())
@@ -7,6 +7,68 @@ mod tests;
 
 pub trait Transform<T> {
     fn transform<'a>(&self, tokens: Vec<Token<'a, T>>) -> Vec<Token<'a, T>>;
+
+    /// Like `transform`, but also given the layout of the match that
+    /// produced `tokens`.
+    fn transform_match<'a>(&self, tokens: Vec<Token<'a, T>>, _m: &Match) -> Vec<Token<'a, T>> {
+        self.transform(tokens)
+    }
+
+    /// Like `transform_match`, for a match of no tokens at all, at byte `at`
+    /// of `root`. Transforms that can make tokens out of nothing make virtual
+    /// tokens there. By default, nothing is made.
+    fn transform_empty<'a>(&self, _root: &'a str, _at: usize, _m: &Match) -> Vec<Token<'a, T>> {
+        vec![]
+    }
+}
+
+/// A transform that may be shared between threads, which is what the `meta`
+/// compiler builds its rules with.
+pub type SyncTransform<T> = dyn Transform<T> + Send + Sync;
+
+impl<T, X: Transform<T> + ?Sized> Transform<T> for std::sync::Arc<X> {
+    fn transform<'a>(&self, tokens: Vec<Token<'a, T>>) -> Vec<Token<'a, T>> {
+        self.as_ref().transform(tokens)
+    }
+
+    fn transform_match<'a>(&self, tokens: Vec<Token<'a, T>>, m: &Match) -> Vec<Token<'a, T>> {
+        self.as_ref().transform_match(tokens, m)
+    }
+
+    fn transform_empty<'a>(&self, root: &'a str, at: usize, m: &Match) -> Vec<Token<'a, T>> {
+        self.as_ref().transform_empty(root, at, m)
+    }
+}
+
+/// A transform defined by a function or closure.
+pub struct FnTransform<F> {
+    pub func: F,
+}
+
+impl<F> FnTransform<F> {
+    pub fn new(func: F) -> FnTransform<F> {
+        FnTransform { func }
+    }
+}
+
+impl<T, F> Transform<T> for FnTransform<F>
+where
+    F: for<'a> Fn(Vec<Token<'a, T>>) -> Vec<Token<'a, T>>,
+{
+    fn transform<'a>(&self, tokens: Vec<Token<'a, T>>) -> Vec<Token<'a, T>> {
+        (self.func)(tokens)
+    }
+}
+
+/// A virtual token with no text, standing in for a node that has nothing
+/// in it.
+fn empty_node<'a, T: Clone>(root: &'a str, at: usize, data: &T) -> Token<'a, T> {
+    Token {
+        root,
+        t_type: TokenType::Virtual(at, None),
+        data: data.clone(),
+        value: None,
+    }
 }
 
 pub struct ShallowTransform<T: Clone> {
@@ -22,11 +84,16 @@ impl<T: Clone> Transform<T> for ShallowTransform<T> {
                     t.content_range().start..tokens.last().unwrap().content_range().end,
                 ),
                 data: self.data.clone(),
+                value: None,
             }]
         } else {
             vec![]
         }
     }
+
+    fn transform_empty<'a>(&self, root: &'a str, at: usize, _m: &Match) -> Vec<Token<'a, T>> {
+        vec![empty_node(root, at, &self.data)]
+    }
 }
 
 pub struct DeepTransform<T: Clone> {
@@ -40,11 +107,161 @@ impl<T: Clone> Transform<T> for DeepTransform<T> {
                 root: t.root,
                 t_type: TokenType::Branch(tokens),
                 data: self.data.clone(),
+                value: None,
             }]
         } else {
             vec![]
         }
     }
+
+    fn transform_empty<'a>(&self, root: &'a str, at: usize, _m: &Match) -> Vec<Token<'a, T>> {
+        vec![empty_node(root, at, &self.data)]
+    }
+}
+
+/// Like `DeepTransform`, but only the selected parts of the match become
+/// children, in the order they're selected in. A token selected more than
+/// once only goes where it's first selected. The branch covers the text from
+/// the first of its children to the last, wherever they are among them. If
+/// nothing is selected (like an optional part that didn't match), the new
+/// token is a virtual one with no children, at the start of the match.
+/// Without a `Match`, each token counts as its own element.
+pub struct SelectTransform<T: Clone> {
+    pub data: T,
+    pub select: Vec<Selector>,
+}
+
+impl<T: Clone> Transform<T> for SelectTransform<T> {
+    fn transform<'a>(&self, tokens: Vec<Token<'a, T>>) -> Vec<Token<'a, T>> {
+        let m = Match {
+            len: tokens.len(),
+            elements: (0..tokens.len()).map(|i| i..i + 1).collect(),
+            captures: vec![],
+        };
+        self.transform_match(tokens, &m)
+    }
+
+    fn transform_match<'a>(&self, tokens: Vec<Token<'a, T>>, m: &Match) -> Vec<Token<'a, T>> {
+        let Some(first) = tokens.first() else {
+            return vec![];
+        };
+        let (root, start) = (first.root, first.content_range().start);
+        let order = self
+            .select
+            .iter()
+            .flat_map(|s| m.select(s))
+            .flatten()
+            .filter(|&i| i < tokens.len())
+            .collect::<Vec<_>>();
+        if order.is_empty() {
+            return vec![empty_node(root, start, &self.data)];
+        }
+
+        let mut slots = tokens.into_iter().map(Some).collect::<Vec<_>>();
+        let children = order
+            .into_iter()
+            .filter_map(|i| slots[i].take())
+            .collect::<Vec<_>>();
+        vec![Token {
+            root,
+            t_type: TokenType::Branch(children),
+            data: self.data.clone(),
+            value: None,
+        }]
+    }
+
+    fn transform_empty<'a>(&self, root: &'a str, at: usize, _m: &Match) -> Vec<Token<'a, T>> {
+        vec![empty_node(root, at, &self.data)]
+    }
+}
+
+/// Splits leaves into several leaves. Each offset in `at` (counted in bytes
+/// from the start of the leaf, in increasing order) starts a new part, and
+/// the nth part gets the nth entry of `data`. If part of the match is marked,
+/// only the marked tokens are split. Branches, and leaves too short to split
+/// at every offset, are left alone, and so is everything if the offsets
+/// aren't increasing or there isn't exactly one entry of `data` per part.
+#[derive(Clone)]
+pub struct SplitTransform<T: Clone> {
+    pub at: Vec<usize>,
+    pub data: Vec<T>,
+}
+
+impl<T: Clone> SplitTransform<T> {
+    pub fn splits(&self, token: &Token<T>) -> bool {
+        if self.data.len() != self.at.len() + 1 || self.at.windows(2).any(|w| w[0] >= w[1]) {
+            return false;
+        }
+        match &token.t_type {
+            TokenType::Leaf(r) => self
+                .at
+                .iter()
+                .all(|&a| a > 0 && a < r.len() && token.root.is_char_boundary(r.start + a)),
+            _ => false,
+        }
+    }
+}
+
+impl<T: Clone> Transform<T> for SplitTransform<T> {
+    fn transform<'a>(&self, tokens: Vec<Token<'a, T>>) -> Vec<Token<'a, T>> {
+        let len = tokens.len();
+        self.transform_match(tokens, &Match::whole(len))
+    }
+
+    fn transform_match<'a>(&self, tokens: Vec<Token<'a, T>>, m: &Match) -> Vec<Token<'a, T>> {
+        let targets = m.marked();
+        let mut new_tox = vec![];
+        for (i, token) in tokens.into_iter().enumerate() {
+            if !targets.contains(&i) || !self.splits(&token) {
+                new_tox.push(token);
+                continue;
+            }
+            let start = token.content_range().start;
+            let mut bounds = vec![start];
+            bounds.extend(self.at.iter().map(|a| start + a));
+            bounds.push(token.content_range().end);
+            for (part, data) in bounds.windows(2).zip(&self.data) {
+                new_tox.push(Token {
+                    root: token.root,
+                    t_type: TokenType::Leaf(part[0]..part[1]),
+                    data: data.clone(),
+                    value: None,
+                });
+            }
+        }
+        new_tox
+    }
+}
+
+/// Keeps the matched tokens, and adds a virtual token right after them, or
+/// where the match is if it's empty.
+pub struct InsertTransform<T: Clone> {
+    pub text: Option<String>,
+    pub data: T,
+}
+
+impl<T: Clone> Transform<T> for InsertTransform<T> {
+    fn transform<'a>(&self, mut tokens: Vec<Token<'a, T>>) -> Vec<Token<'a, T>> {
+        if let Some(t) = tokens.last() {
+            let new_tok = Token {
+                root: t.root,
+                t_type: TokenType::Virtual(t.content_range().end, self.text.clone()),
+                data: self.data.clone(),
+                value: None,
+            };
+            tokens.push(new_tok);
+        }
+        tokens
+    }
+
+    fn transform_empty<'a>(&self, root: &'a str, at: usize, _m: &Match) -> Vec<Token<'a, T>> {
+        vec![Token {
+            root,
+            t_type: TokenType::Virtual(at, self.text.clone()),
+            data: self.data.clone(),
+            value: None,
+        }]
+    }
 }
 
 pub struct RemoveTransform {}
@@ -55,6 +272,21 @@ impl<T> Transform<T> for RemoveTransform {
     }
 }
 
+/// Replaces each branch token with its children, leaving leaves alone.
+pub struct FlattenTransform {}
+
+impl<T> Transform<T> for FlattenTransform {
+    fn transform<'a>(&self, tokens: Vec<Token<'a, T>>) -> Vec<Token<'a, T>> {
+        tokens
+            .into_iter()
+            .flat_map(|t| match t.t_type {
+                TokenType::Branch(children) => children,
+                _ => vec![t],
+            })
+            .collect()
+    }
+}
+
 pub fn repeat_until_no_change<T>(funcs: &Vec<&dyn Fn(&mut T) -> bool>, carry_over: &mut T) -> bool {
     let mut changed_at_least_once = false;
     let mut i = 0;
@@ -16,3 +16,103 @@ pub fn remove_a_test(text: &str, expected: &str) {
     replace_all_matches(&seq, &trans, tox);
     assert_eq!(expected, Token::vec_content(tox));
 }
+
+#[test_case("abc", "abc", 3; "flattening a branch")]
+#[test_case("", "", 0; "no letters")]
+pub fn flatten_test(text: &str, expected: &str, expected_len: usize) {
+    let tox = &mut Token::token_vec_from_str(text, |_, _| ());
+    replace_all_matches_once(
+        &RepeatedSeq::new(Box::new(FirstTokenSeq::new(|_| true))),
+        &DeepTransform { data: () },
+        tox,
+    );
+    replace_all_matches_once(&FirstTokenSeq::new(|_| true), &FlattenTransform {}, tox);
+    assert_eq!(expected, Token::vec_content(tox));
+    assert_eq!(expected_len, tox.len());
+}
+
+#[test_case("abc", vec![Selector::Element(2), Selector::Element(0)], "ca"; "selecting out of order")]
+#[test_case("abc", vec![Selector::Element(1), Selector::Element(1)], "b"; "selecting twice")]
+pub fn select_test(text: &str, select: Vec<Selector>, expected: &str) {
+    let tox = Token::token_vec_from_str(text, |_, _| ());
+    let new_tox = SelectTransform { data: (), select }.transform(tox);
+    assert_eq!(new_tox.len(), 1);
+    let children = new_tox[0].children().unwrap();
+    assert_eq!(expected, Token::vec_content(children));
+    assert_eq!(new_tox[0].content().len(), new_tox[0].content_range().len());
+}
+
+#[test]
+pub fn select_nothing_test() {
+    let tox = Token::token_vec_from_str("abc", |_, _| ());
+    let new_tox = SelectTransform {
+        data: (),
+        select: vec![],
+    }
+    .transform(tox);
+    assert_eq!(new_tox.len(), 1);
+    assert!(new_tox[0].children().is_none());
+    assert_eq!(new_tox[0].content(), "");
+    assert_eq!(new_tox[0].content_range(), 0..0);
+}
+
+#[test_case(None, ""; "inserting nothing")]
+#[test_case(Some(";"), ";"; "inserting text")]
+pub fn insert_empty_test(insert: Option<&str>, expected: &str) {
+    let new_tox = InsertTransform {
+        text: insert.map(str::to_owned),
+        data: (),
+    }
+    .transform_empty("ab", 1, &Match::whole(0));
+    assert_eq!(new_tox.len(), 1);
+    assert_eq!(new_tox[0].content(), expected);
+    assert_eq!(new_tox[0].content_range(), 1..1);
+}
+
+#[test]
+pub fn deep_empty_test() {
+    let new_tox = DeepTransform { data: 0 }.transform_empty("ab", 2, &Match::whole(0));
+    assert_eq!(new_tox.len(), 1);
+    assert!(new_tox[0].children().is_none());
+    assert_eq!(new_tox[0].content(), "");
+    assert_eq!(new_tox[0].content_range(), 2..2);
+}
+
+#[test_case("ab", Some(";"), "ab;", 3; "inserting text")]
+#[test_case("ab", None, "ab", 3; "inserting nothing")]
+#[test_case("", Some(";"), "", 0; "no letters")]
+pub fn insert_test(text: &str, insert: Option<&str>, expected: &str, expected_len: usize) {
+    let tox = Token::token_vec_from_str(text, |_, _| ());
+    let new_tox = InsertTransform {
+        text: insert.map(str::to_owned),
+        data: (),
+    }
+    .transform(tox);
+    assert_eq!(expected, Token::vec_content(&new_tox));
+    assert_eq!(expected_len, new_tox.len());
+    if let Some(last) = new_tox.last() {
+        assert_eq!(last.content_range(), 2..2);
+    }
+}
+
+#[test_case("abcd", vec![1, 3], vec!["a", "bc", "d"]; "splitting into three")]
+#[test_case("ab", vec![2], vec!["ab"]; "offset past the end")]
+pub fn split_test(text: &str, at: Vec<usize>, expected: Vec<&str>) {
+    let tox = Token::token_vec_from_str(text, |_, _| 0);
+    let tox = ShallowTransform { data: 0 }.transform(tox);
+    let data = (1..=at.len() + 1).collect();
+    let new_tox = SplitTransform { at, data }.transform(tox);
+    let parts = new_tox.iter().map(|t| t.content()).collect::<Vec<_>>();
+    assert_eq!(parts, expected);
+}
+
+#[test_case(vec![1, 3], vec![1, 2]; "too little data")]
+#[test_case(vec![1, 3], vec![1, 2, 3, 4]; "too much data")]
+#[test_case(vec![3, 1], vec![1, 2, 3]; "offsets out of order")]
+pub fn split_mismatch_test(at: Vec<usize>, data: Vec<usize>) {
+    let tox = Token::token_vec_from_str("abcd", |_, _| 0);
+    let tox = ShallowTransform { data: 0 }.transform(tox);
+    let new_tox = SplitTransform { at, data }.transform(tox);
+    assert_eq!(new_tox.len(), 1);
+    assert_eq!(new_tox[0].content(), "abcd");
+}